@@ -0,0 +1,116 @@
+//! Measures the per-frame cost of sending audio, which is Opus encoding followed by
+//! building and encrypting the RTP packet, for every encryption mode.
+//! The number of allocations per frame is printed after each mode.
+//! Run with `cargo bench --no-default-features`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use _native_voice::payloads::EncryptionMode;
+use _native_voice::player::{AudioEncoder, EncoderOptions, DEFAULT_BITRATE};
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const MODES: [EncryptionMode; 4] = [
+    EncryptionMode::XSalsa20Poly1305,
+    EncryptionMode::XSalsa20Poly1305Suffix,
+    EncryptionMode::XSalsa20Poly1305Lite,
+    EncryptionMode::AeadXChaCha20Poly1305RtpSize,
+];
+
+/// The size of the Opus data encrypted in the AEAD only benchmark,
+/// roughly what a 128kbps stereo frame encodes to.
+const OPUS_FRAME_SIZE: usize = 320;
+
+fn encoder(mode: EncryptionMode) -> AudioEncoder {
+    let mut encoder = AudioEncoder::new(
+        mode,
+        &[7; 32],
+        1234,
+        DEFAULT_BITRATE,
+        &EncoderOptions::default(),
+    )
+    .expect("could not create encoder");
+
+    // A 440Hz tone so Opus has something to encode
+    for (i, sample) in encoder.pcm_buffer_mut().chunks_exact_mut(2).enumerate() {
+        let t = i as f32 / 48000.0;
+        let value = ((t * 440.0 * 2.0 * std::f32::consts::PI).sin() * 8000.0) as i16;
+        sample[0] = value;
+        sample[1] = value;
+    }
+    encoder
+}
+
+fn encode_frame(encoder: &mut AudioEncoder) -> usize {
+    let size = encoder.encode_pcm_buffer().expect("could not encode");
+    encoder.prepare_packet(size).expect("could not encrypt")
+}
+
+fn mode_name(mode: EncryptionMode) -> String {
+    mode.into()
+}
+
+fn report_allocations(name: &str, mode: EncryptionMode, mut frame: impl FnMut()) {
+    const FRAMES: usize = 1000;
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    for _ in 0..FRAMES {
+        frame();
+    }
+    let count = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    println!(
+        "{}/{}: {:.2} allocations per frame",
+        name,
+        mode_name(mode),
+        count as f64 / FRAMES as f64
+    );
+}
+
+fn bench_encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode and encrypt");
+    for &mode in MODES.iter() {
+        let mut encoder = encoder(mode);
+        group.bench_function(BenchmarkId::from_parameter(mode_name(mode)), |b| {
+            b.iter(|| black_box(encode_frame(&mut encoder)))
+        });
+        report_allocations("encode and encrypt", mode, || {
+            black_box(encode_frame(&mut encoder));
+        });
+    }
+    group.finish();
+}
+
+fn bench_aead(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encrypt");
+    for &mode in MODES.iter() {
+        let mut encoder = encoder(mode);
+        group.bench_function(BenchmarkId::from_parameter(mode_name(mode)), |b| {
+            b.iter(|| black_box(encoder.prepare_packet(OPUS_FRAME_SIZE).unwrap()))
+        });
+        report_allocations("encrypt", mode, || {
+            black_box(encoder.prepare_packet(OPUS_FRAME_SIZE).unwrap());
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_encode, bench_aead);
+criterion_main!(benches);
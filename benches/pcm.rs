@@ -0,0 +1,99 @@
+//! Compares decoding PCM explicitly as little-endian against reinterpreting the bytes
+//! in place, which is what the sources used to do, and reading frames from a pipe
+//! one at a time against reading them through a buffer.
+//! Run with `cargo bench --no-default-features`.
+
+use std::io::{Cursor, Read};
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+
+use _native_voice::player::{buffered_pcm_reader, decode_pcm_le, FRAME_SIZE};
+
+/// The number of frames in the simulated pipe, ten seconds of audio
+const PIPE_FRAMES: usize = 500;
+
+fn transmute_copy(bytes: &[u8], buffer: &mut [i16]) {
+    let samples =
+        unsafe { std::slice::from_raw_parts(bytes.as_ptr() as *const i16, bytes.len() / 2) };
+    buffer.copy_from_slice(samples);
+}
+
+/// Stands in for FFmpeg's stdout, counting every read as it would be a syscall
+struct CountingReader {
+    inner: Cursor<Vec<u8>>,
+    reads: usize,
+}
+
+impl CountingReader {
+    fn new() -> Self {
+        let data = vec![0u8; FRAME_SIZE as usize * PIPE_FRAMES];
+        Self {
+            inner: Cursor::new(data),
+            reads: 0,
+        }
+    }
+}
+
+impl Read for CountingReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.reads += 1;
+        self.inner.read(buf)
+    }
+}
+
+/// Reads every frame out of the reader, returning the number of frames read
+fn read_frames<R: Read>(reader: &mut R) -> usize {
+    let mut frame = vec![0u8; FRAME_SIZE as usize];
+    let mut frames = 0;
+    while reader.read_exact(&mut frame).is_ok() {
+        frames += 1;
+    }
+    frames
+}
+
+fn bench_decode(c: &mut Criterion) {
+    let bytes: Vec<u8> = (0..FRAME_SIZE).map(|i| (i % 251) as u8).collect();
+    let mut buffer = vec![0i16; FRAME_SIZE as usize / 2];
+
+    let mut group = c.benchmark_group("pcm frame");
+    group.bench_function("transmute", |b| {
+        b.iter(|| transmute_copy(black_box(&bytes), &mut buffer))
+    });
+    group.bench_function("from_le_bytes", |b| {
+        b.iter(|| decode_pcm_le(black_box(&bytes), &mut buffer))
+    });
+    group.finish();
+}
+
+fn bench_read(c: &mut Criterion) {
+    let mut group = c.benchmark_group("pipe read");
+    // The pipe's data is set up outside of the measurement
+    group.bench_function("unbuffered", |b| {
+        b.iter_batched(
+            CountingReader::new,
+            |mut reader| read_frames(&mut reader),
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("buffered", |b| {
+        b.iter_batched(
+            || buffered_pcm_reader(CountingReader::new(), FRAME_SIZE as usize),
+            |mut reader| read_frames(&mut reader),
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+
+    let mut unbuffered = CountingReader::new();
+    read_frames(&mut unbuffered);
+    let mut buffered = buffered_pcm_reader(CountingReader::new(), FRAME_SIZE as usize);
+    read_frames(&mut buffered);
+    println!(
+        "pipe read: {:.2} reads per frame unbuffered, {:.2} buffered",
+        unbuffered.reads as f64 / PIPE_FRAMES as f64,
+        buffered.get_ref().reads as f64 / PIPE_FRAMES as f64
+    );
+}
+
+criterion_group!(benches, bench_decode, bench_read);
+criterion_main!(benches);
@@ -3,11 +3,18 @@ from . import _native_voice as _native
 import discord
 import asyncio
 import logging
+import shlex
 
 from discord.backoff import ExponentialBackoff
 
 log = logging.getLogger(__name__)
 
+def bitrate_range():
+    return _native.bitrate_range()
+
+def bandwidths():
+    return _native.bandwidths()
+
 class VoiceClient(discord.VoiceProtocol):
     def __init__(self, client, channel):
         super().__init__(client, channel)
@@ -53,6 +60,9 @@ class VoiceClient(discord.VoiceProtocol):
             endpoint = endpoint[6:]
 
         self._connector.update_socket(token, server_id, endpoint)
+        if self._connection is not None:
+            # Picked up the next time the connection reconnects
+            self._connection.update_credentials(token, endpoint, self._connector.session_id)
         self._voice_server_complete.set()
 
     async def voice_connect(self):
@@ -89,7 +99,7 @@ class VoiceClient(discord.VoiceProtocol):
         self._voice_state_complete.clear()
 
         loop = asyncio.get_running_loop()
-        self._connection = await self._connector.connect(loop)
+        self._connection = await self._connector.connect_and_run(loop)
         if self._runner is not None:
             self._runner.cancel()
 
@@ -97,11 +107,10 @@ class VoiceClient(discord.VoiceProtocol):
 
     async def reconnect_handler(self, reconnect, timeout):
         backoff = ExponentialBackoff()
-        loop = asyncio.get_running_loop()
 
         while True:
             try:
-                await self._connection.run(loop)
+                await self._connection.closed
             except _native_voice.ConnectionClosed as e:
                 log.info('Voice connection got a clean close %s', e)
                 await self.disconnect()
@@ -135,26 +144,250 @@ class VoiceClient(discord.VoiceProtocol):
     async def disconnect(self, *, force=False):
         try:
             if self._connection is not None:
-                self._connection.disconnect()
+                loop = asyncio.get_running_loop()
+                await loop.run_in_executor(None, self._connection.disconnect)
                 self._connection = None
 
             await self.voice_disconnect()
         finally:
             self.cleanup()
 
-    def play(self, title):
+    async def reconnect(self, *, token, endpoint, session_id):
+        if self._connection:
+            loop = asyncio.get_running_loop()
+            await loop.run_in_executor(None, self._connection.reconnect, token, endpoint, session_id)
+
+    def play(self, title, *, after=None, before_options=None, options=None, speaking=discord.SpeakingState.voice, send_policy='drop', downmix=False, limiter=None, prebuffer=5):
+        if self._connection:
+            if before_options is None and options is None:
+                self._connection.play(title, after, int(speaking), send_policy, downmix, limiter, prebuffer)
+            else:
+                before_options = shlex.split(before_options or '')
+                options = shlex.split(options or '')
+                self._connection.play_with_options(title, before_options, options, after, int(speaking), send_policy, downmix, limiter, prebuffer)
+
+    def play_pipe(self, file, *, after=None, before_options=None, options=None, speaking=discord.SpeakingState.voice, send_policy='drop', downmix=False, limiter=None, prebuffer=5):
+        if self._connection:
+            before_options = shlex.split(before_options or '')
+            options = shlex.split(options or '')
+            self._connection.play_pipe(file, before_options, options, after, int(speaking), send_policy, downmix, limiter, prebuffer)
+
+    def play_pcm(self, data, *, after=None, speaking=discord.SpeakingState.voice, send_policy='drop'):
+        if self._connection:
+            self._connection.play_pcm(data, after, int(speaking), send_policy)
+
+    def play_opus(self, source, *, after=None, speaking=discord.SpeakingState.voice, send_policy='drop'):
+        if self._connection:
+            self._connection.play_opus(source, after, int(speaking), send_policy)
+
+    def play_dca(self, path, *, after=None, speaking=discord.SpeakingState.voice, send_policy='drop'):
+        if self._connection:
+            self._connection.play_dca(path, after, int(speaking), send_policy)
+
+    def enqueue(self, title):
+        if self._connection:
+            self._connection.enqueue(title)
+
+    def skip(self):
+        if self._connection:
+            self._connection.skip()
+
+    def mute(self):
+        if self._connection:
+            self._connection.mute()
+
+    def unmute(self):
+        if self._connection:
+            self._connection.unmute()
+
+    def is_muted(self):
+        return self._connection is not None and self._connection.is_muted
+
+    def send_raw_opus(self, frame):
+        if self._connection:
+            self._connection.send_raw_opus(frame)
+
+    def set_loop(self, enabled):
+        if self._connection:
+            self._connection.set_loop(enabled)
+
+    @property
+    def looping(self):
+        return self._connection is not None and self._connection.looping
+
+    def swap_source(self, title):
+        if self._connection:
+            self._connection.swap_source(title)
+
+    def crossfade_to(self, title, duration_ms):
+        if self._connection:
+            self._connection.crossfade_to(title, duration_ms)
+
+    @property
+    def preferred_encryption(self):
+        return self._connector.preferred_encryption
+
+    @preferred_encryption.setter
+    def preferred_encryption(self, mode):
+        self._connector.preferred_encryption = mode
+
+    @property
+    def preferred_ip_family(self):
+        return self._connector.preferred_ip_family
+
+    @preferred_ip_family.setter
+    def preferred_ip_family(self, family):
+        self._connector.preferred_ip_family = family
+
+    @property
+    def max_message_size(self):
+        return self._connector.max_message_size
+
+    @max_message_size.setter
+    def max_message_size(self, size):
+        self._connector.max_message_size = size
+
+    def set_timeouts(self, *, connect=10.0, read=0.1):
+        self._connector.set_timeouts(connect, read)
+
+    def set_tls_options(self, *, danger_accept_invalid_certs=False, root_certificate=None):
+        if isinstance(root_certificate, str):
+            root_certificate = root_certificate.encode()
+        self._connector.set_tls_options(danger_accept_invalid_certs, root_certificate)
+
+    def set_reconnect_policy(self, *, base_delay=1.0, max_delay=60.0, multiplier=2.0, max_attempts=5):
+        self._connector.set_reconnect_policy(base_delay, max_delay, multiplier, max_attempts)
+        if self._connection:
+            self._connection.set_reconnect_policy(base_delay, max_delay, multiplier, max_attempts)
+
+    def send_speaking(self, *, microphone=True, soundshare=False, priority=False, delay=0):
+        if self._connection:
+            self._connection.send_speaking(microphone, soundshare, priority, delay)
+
+    def set_speaking(self, flags):
+        if self._connection:
+            self._connection.set_speaking(int(flags))
+
+    def set_bitrate(self, bits_per_second):
+        if self._connection:
+            self._connection.set_bitrate(bits_per_second)
+
+    def set_packet_loss_simulation(self, drop_pct, reorder_pct, *, seed=0):
         if self._connection:
-            self._connection.play(title)
+            self._connection.set_packet_loss_simulation(drop_pct, reorder_pct, seed)
+
+    def set_source_timeout(self, seconds):
+        if self._connection:
+            self._connection.set_source_timeout(seconds)
+
+    def set_send_timeout(self, seconds):
+        if self._connection:
+            self._connection.set_send_timeout(seconds)
+
+    def set_encoder_options(self, *, fec=True, packet_loss_percentage=15, signal='auto', bandwidth='full', channels=2, silence_threshold=None, frame_length=20, bitrate_mode='constrained'):
+        if self._connection:
+            self._connection.set_encoder_options(fec, packet_loss_percentage, signal, bandwidth, channels, silence_threshold, frame_length, bitrate_mode)
+
+    @staticmethod
+    async def probe(input):
+        loop = asyncio.get_running_loop()
+        return await _native.VoiceConnection.probe(loop, input)
+
+    def add_mix_source(self, source, *, gain=1.0):
+        if self._connection:
+            self._connection.add_mix_source(source, gain)
+
+    def seek(self, seconds):
+        if self._connection:
+            self._connection.seek(seconds)
 
     def stop(self):
         if self._connection:
             self._connection.stop()
 
+    async def shutdown(self, *, timeout=5.0):
+        if self._connection is None:
+            return True
+        loop = asyncio.get_running_loop()
+        return await loop.run_in_executor(None, self._connection.shutdown, timeout)
+
+    def listen(self, callback):
+        if self._connection:
+            self._connection.listen(callback)
+
+    def pcm_tap(self, callback):
+        if self._connection:
+            self._connection.pcm_tap(callback)
+
+    def on_event(self, callback):
+        if self._connection:
+            self._connection.on_event(callback)
+
+    def on_client_disconnect(self, callback):
+        if self._connection:
+            self._connection.on_client_disconnect(callback)
+
+    def stop_listening(self):
+        if self._connection:
+            self._connection.stop_listening()
+
+    def is_listening(self):
+        if self._connection:
+            return self._connection.is_listening()
+        return False
+
+    def is_connected(self):
+        if self._connection:
+            return self._connection.is_connected()
+        return False
+
     def is_playing(self):
         if self._connection:
             return self._connection.is_playing()
         return False
 
+    @property
+    def playback_position(self):
+        if self._connection:
+            return self._connection.playback_position
+        return 0.0
+
+    @property
+    def source_info(self):
+        if self._connection:
+            return self._connection.source_info
+        return None
+
+    @property
+    def encoder_info(self):
+        if self._connection:
+            return self._connection.encoder_info
+        return None
+
+    @property
+    def performance_stats(self):
+        if self._connection:
+            return self._connection.performance_stats
+        return None
+
+    def speaking_states(self):
+        if self._connection:
+            return self._connection.get_speaking_states()
+        return {}
+
+    def export_state(self):
+        if self._connection:
+            return self._connection.get_state()
+        return None
+
+    async def restore(self, state, *, reconnect=True, timeout=60.0):
+        loop = asyncio.get_running_loop()
+        self._connection = await self._connector.restore(state, loop)
+        if self._runner is not None:
+            self._runner.cancel()
+
+        self._runner = loop.create_task(self.reconnect_handler(reconnect, timeout))
+
     def _debug_info(self):
         if self._connection:
             return self._connection.get_state()
@@ -7,7 +7,25 @@ pub enum ProtocolError {
     Nacl(xsalsa20poly1305::aead::Error),
     WebSocket(tungstenite::error::Error),
     Io(std::io::Error),
-    Closed(u16),
+    /// The close code and the reason given in the close frame, if any
+    Closed(u16, Option<String>),
+    /// The audio source produced no data within the given timeout
+    SourceStalled(std::time::Duration),
+    /// FFmpeg exited unsuccessfully with the given exit code (None if killed by a signal)
+    /// and the last few lines it wrote to stderr
+    FFmpeg(Option<i32>, String),
+    /// UDP discovery got no valid response within the given number of attempts
+    UdpDiscovery(u32),
+    /// Connecting or reading timed out
+    Timeout(std::io::Error),
+    /// The host name could not be resolved
+    Dns(std::io::Error),
+    /// The host actively refused the connection
+    ConnectionRefused(std::io::Error),
+    /// A websocket message was larger than the given maximum size in bytes
+    MessageTooLarge(usize),
+    /// The given FFmpeg program (ffmpeg or ffprobe) could not be found to run
+    FFmpegNotFound(&'static str),
 }
 
 pub(crate) fn custom_error(text: &str) -> ProtocolError {
@@ -15,6 +33,41 @@ pub(crate) fn custom_error(text: &str) -> ProtocolError {
     ProtocolError::Io(inner)
 }
 
+/// Converts an error from connecting a socket into the variant for its cause,
+/// so callers can tell the failures worth retrying apart.
+/// Anything that isn't a timeout or a refused connection stays an Io error.
+pub(crate) fn connect_error(err: std::io::Error) -> ProtocolError {
+    match err.kind() {
+        std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => {
+            ProtocolError::Timeout(err)
+        }
+        std::io::ErrorKind::ConnectionRefused => ProtocolError::ConnectionRefused(err),
+        _ => ProtocolError::Io(err),
+    }
+}
+
+impl ProtocolError {
+    /// A short name describing the variant, exposed to Python as the `kind` attribute
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ProtocolError::Serde(_) => "serde",
+            ProtocolError::Opus(_) => "opus",
+            ProtocolError::Nacl(_) => "nacl",
+            ProtocolError::WebSocket(_) => "websocket",
+            ProtocolError::Io(_) => "io",
+            ProtocolError::Closed(..) => "closed",
+            ProtocolError::SourceStalled(_) => "source_stalled",
+            ProtocolError::FFmpeg(..) => "ffmpeg",
+            ProtocolError::UdpDiscovery(_) => "udp_discovery",
+            ProtocolError::Timeout(_) => "timeout",
+            ProtocolError::Dns(_) => "dns",
+            ProtocolError::ConnectionRefused(_) => "connection_refused",
+            ProtocolError::MessageTooLarge(_) => "message_too_large",
+            ProtocolError::FFmpegNotFound(_) => "ffmpeg_not_found",
+        }
+    }
+}
+
 impl std::fmt::Display for ProtocolError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -23,9 +76,47 @@ impl std::fmt::Display for ProtocolError {
             ProtocolError::Opus(ref e) => e.fmt(f),
             ProtocolError::Nacl(ref e) => e.fmt(f),
             ProtocolError::Io(ref e) => e.fmt(f),
-            ProtocolError::Closed(code) => {
+            ProtocolError::Timeout(ref e) => write!(f, "connection timed out: {}", e),
+            ProtocolError::Dns(ref e) => write!(f, "could not resolve host: {}", e),
+            ProtocolError::ConnectionRefused(ref e) => write!(f, "connection refused: {}", e),
+            ProtocolError::Closed(code, Some(reason)) => write!(
+                f,
+                "WebSocket connection closed (code: {}, reason: {})",
+                code, reason
+            ),
+            ProtocolError::Closed(code, None) => {
                 write!(f, "WebSocket connection closed (code: {})", code)
             }
+            ProtocolError::SourceStalled(timeout) => write!(
+                f,
+                "audio source produced no data for {:.1} seconds",
+                timeout.as_secs_f64()
+            ),
+            ProtocolError::FFmpeg(code, stderr) => {
+                match code {
+                    Some(code) => write!(f, "ffmpeg exited with status {}", code)?,
+                    None => write!(f, "ffmpeg was terminated by a signal")?,
+                }
+                if !stderr.is_empty() {
+                    write!(f, ":\n{}", stderr)?;
+                }
+                Ok(())
+            }
+            ProtocolError::UdpDiscovery(attempts) => write!(
+                f,
+                "UDP discovery failed after {} attempts, outgoing UDP is likely blocked",
+                attempts
+            ),
+            ProtocolError::MessageTooLarge(limit) => write!(
+                f,
+                "websocket message is larger than the maximum of {} bytes",
+                limit
+            ),
+            ProtocolError::FFmpegNotFound(program) => write!(
+                f,
+                "{} was not found, make sure FFmpeg is installed and on the PATH",
+                program
+            ),
         }
     }
 }
@@ -37,8 +128,16 @@ impl std::error::Error for ProtocolError {
             ProtocolError::WebSocket(ref e) => Some(e),
             ProtocolError::Opus(ref e) => Some(e),
             ProtocolError::Io(ref e) => Some(e),
+            ProtocolError::Timeout(ref e) => Some(e),
+            ProtocolError::Dns(ref e) => Some(e),
+            ProtocolError::ConnectionRefused(ref e) => Some(e),
             ProtocolError::Nacl(_) => None,
-            ProtocolError::Closed(_) => None,
+            ProtocolError::Closed(..) => None,
+            ProtocolError::SourceStalled(_) => None,
+            ProtocolError::FFmpeg(..) => None,
+            ProtocolError::UdpDiscovery(_) => None,
+            ProtocolError::MessageTooLarge(_) => None,
+            ProtocolError::FFmpegNotFound(_) => None,
         }
     }
 }
@@ -1,38 +1,83 @@
 use pyo3::create_exception;
 use pyo3::prelude::*;
-use pyo3::types::{PyBytes, PyDict};
+use pyo3::types::{PyBytes, PyDict, PyTuple};
+use pyo3::wrap_pyfunction;
 
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
 use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 
-use parking_lot::Mutex;
+use parking_lot::{Mutex, MutexGuard};
 
 pub mod error;
 pub mod payloads;
 pub mod player;
 pub mod protocol;
+pub mod receiver;
 pub(crate) mod state;
+pub mod transport;
 
 create_exception!(_native_voice, ReconnectError, pyo3::exceptions::PyException);
 create_exception!(_native_voice, ConnectionError, pyo3::exceptions::PyException);
 create_exception!(_native_voice, ConnectionClosed, pyo3::exceptions::PyException);
+create_exception!(_native_voice, FFmpegNotFound, pyo3::exceptions::PyException);
 
+/// Whether the connection is reconnected after closing with the given code.
+/// protocol::close_code_resumable decides whether that resumes the session.
 fn code_can_be_handled(code: u16) -> bool {
-    // Non-resumable close-codes are:
+    // Close codes that are not reconnected after:
     // 1000 - normal closure
     // 4014 - voice channel deleted
     // 4015 - voice server crash
     code != 1000 && code != 4014 && code != 4015
 }
 
+/// Creates an exception of the given type with extra attributes set on the instance
+fn exception_with_attrs<T, A>(py: Python, args: A, attrs: &[(&str, PyObject)]) -> PyErr
+where
+    T: pyo3::type_object::PyTypeObject,
+    A: IntoPy<Py<PyTuple>>,
+{
+    let result = py.get_type::<T>().call1(args).and_then(|instance| {
+        for (name, value) in attrs {
+            instance.setattr(*name, value)?;
+        }
+        Ok(PyErr::from_instance(instance))
+    });
+
+    match result {
+        Ok(err) | Err(err) => err,
+    }
+}
+
 impl std::convert::From<error::ProtocolError> for PyErr {
     fn from(err: error::ProtocolError) -> Self {
         match err {
-            error::ProtocolError::Closed(code) if code_can_be_handled(code) => {
-                ReconnectError::new_err(code)
+            error::ProtocolError::Closed(code, reason) => {
+                let gil = Python::acquire_gil();
+                let py = gil.python();
+                let attrs = [
+                    ("code", code.to_object(py)),
+                    ("reason", reason.to_object(py)),
+                ];
+                if code_can_be_handled(code) {
+                    exception_with_attrs::<ReconnectError, _>(py, (code,), &attrs)
+                } else {
+                    exception_with_attrs::<ConnectionClosed, _>(py, (code,), &attrs)
+                }
+            }
+            // Nothing to do with the connection, so this gets its own exception
+            error::ProtocolError::FFmpegNotFound(_) => FFmpegNotFound::new_err(err.to_string()),
+            _ => {
+                let gil = Python::acquire_gil();
+                let py = gil.python();
+                exception_with_attrs::<ConnectionError, _>(
+                    py,
+                    (err.to_string(),),
+                    &[("kind", err.kind().to_object(py))],
+                )
             }
-            error::ProtocolError::Closed(code) => ConnectionClosed::new_err(code),
-            _ => ConnectionError::new_err(err.to_string()),
         }
     }
 }
@@ -49,14 +94,406 @@ fn set_exception(py: Python, loop_: PyObject, future: PyObject, exception: PyErr
     Ok(())
 }
 
+fn check_limiter(limiter: Option<f32>) -> PyResult<()> {
+    match limiter {
+        Some(threshold) if !(threshold > 0.0 && threshold <= 1.0) => Err(
+            pyo3::exceptions::PyValueError::new_err("limiter threshold must be between 0 and 1"),
+        ),
+        _ => Ok(()),
+    }
+}
+
+fn probe_info_to_dict(py: Python, info: player::ProbeInfo) -> PyResult<PyObject> {
+    let result = PyDict::new(py);
+    result.set_item("duration", info.duration)?;
+    result.set_item("bitrate", info.bitrate)?;
+    result.set_item("codec", info.codec)?;
+    result.set_item("title", info.title)?;
+    result.set_item("artist", info.artist)?;
+    Ok(result.to_object(py))
+}
+
+/// The Python callbacks for what happens on the background threads.
+/// They can be set at any time and are scheduled on the event loop when called.
+#[derive(Default)]
+struct Callbacks {
+    /// Called with a dict with a "type" key for every event
+    event: Mutex<Option<PyObject>>,
+    /// Called with the user ID of each user that leaves the channel
+    client_disconnect: Mutex<Option<PyObject>>,
+}
+
+fn schedule(py: Python, loop_: &PyObject, callback: &PyObject, arg: PyObject) {
+    let args = (callback.clone_ref(py), arg);
+    if let Err(e) = loop_.call_method1(py, "call_soon_threadsafe", args) {
+        e.print(py);
+    }
+}
+
+impl Callbacks {
+    /// Schedules the event callback, if any, with the given event dict
+    fn emit(&self, py: Python, loop_: &PyObject, event: &PyDict) {
+        if let Some(callback) = self.event.lock().as_ref() {
+            schedule(py, loop_, callback, event.to_object(py));
+        }
+    }
+
+    /// Reports the events taken from the protocol.
+    /// This takes the GIL so it mustn't be called with the protocol locked, since Python
+    /// code holding the GIL can be waiting on that lock.
+    fn dispatch(&self, loop_: &PyObject, events: Vec<protocol::ProtocolEvent>) {
+        if events.is_empty() {
+            return;
+        }
+
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+        for event in events {
+            if let protocol::ProtocolEvent::ClientDisconnect(user_id) = event {
+                if let Some(callback) = self.client_disconnect.lock().as_ref() {
+                    schedule(py, loop_, callback, user_id.to_object(py));
+                }
+            }
+            match protocol_event_to_dict(py, &event) {
+                Ok(dict) => self.emit(py, loop_, dict),
+                Err(e) => e.print(py),
+            }
+        }
+    }
+}
+
+fn protocol_event_to_dict<'py>(
+    py: Python<'py>,
+    event: &protocol::ProtocolEvent,
+) -> PyResult<&'py PyDict> {
+    let result = PyDict::new(py);
+    match *event {
+        protocol::ProtocolEvent::Connected { resumed } => {
+            result.set_item("type", "connected")?;
+            result.set_item("resumed", resumed)?;
+        }
+        protocol::ProtocolEvent::Speaking {
+            user_id,
+            ssrc,
+            speaking,
+        } => {
+            result.set_item("type", "speaking")?;
+            result.set_item("user_id", user_id)?;
+            result.set_item("ssrc", ssrc)?;
+            result.set_item("speaking", speaking)?;
+        }
+        protocol::ProtocolEvent::Latency(latency) => {
+            result.set_item("type", "latency")?;
+            result.set_item("latency", latency)?;
+        }
+        protocol::ProtocolEvent::ClientConnect { user_id, ssrc } => {
+            result.set_item("type", "client_connect")?;
+            result.set_item("user_id", user_id)?;
+            result.set_item("ssrc", ssrc)?;
+        }
+        protocol::ProtocolEvent::ClientDisconnect(user_id) => {
+            result.set_item("type", "client_disconnect")?;
+            result.set_item("user_id", user_id)?;
+        }
+        protocol::ProtocolEvent::HeartbeatMismatch { expected, received } => {
+            result.set_item("type", "heartbeat_mismatch")?;
+            result.set_item("expected", expected)?;
+            result.set_item("received", received)?;
+        }
+    }
+    Ok(result)
+}
+
+fn reconnecting_event(py: Python, code: u16, attempt: u32, delay: Duration) -> PyResult<&PyDict> {
+    let result = PyDict::new(py);
+    result.set_item("type", "reconnecting")?;
+    result.set_item("code", code)?;
+    result.set_item("attempt", attempt)?;
+    result.set_item("delay", delay.as_secs_f64())?;
+    Ok(result)
+}
+
+fn track_finished_event<'py>(
+    py: Python<'py>,
+    index: usize,
+    reason: &str,
+    error: PyObject,
+) -> PyResult<&'py PyDict> {
+    let result = PyDict::new(py);
+    result.set_item("type", "track_finished")?;
+    result.set_item("index", index)?;
+    result.set_item("reason", reason)?;
+    result.set_item("error", error)?;
+    Ok(result)
+}
+
+/// Attempts to resume the session according to the protocol's reconnect policy.
+/// Returns the original close error once there are no attempts remaining.
+fn reconnect_with_policy(
+    proto: &Mutex<protocol::DiscordVoiceProtocol>,
+    loop_: &PyObject,
+    callbacks: &Callbacks,
+    code: u16,
+    reason: Option<String>,
+) -> Result<(), error::ProtocolError> {
+    loop {
+        let (delay, attempt) = {
+            let mut proto = proto.lock();
+            (proto.next_reconnect_delay(), proto.reconnect_attempts)
+        };
+        let delay = match delay {
+            Some(delay) => delay,
+            None => return Err(error::ProtocolError::Closed(code, reason)),
+        };
+
+        {
+            let gil = Python::acquire_gil();
+            let py = gil.python();
+            match reconnecting_event(py, code, attempt, delay) {
+                Ok(event) => callbacks.emit(py, loop_, event),
+                Err(e) => e.print(py),
+            }
+        }
+        thread::sleep(delay);
+        // A failed attempt is retried after the next delay until the policy gives up
+        if proto.lock().reconnect().is_ok() {
+            return Ok(());
+        }
+    }
+}
+
+/// Polls the protocol until it fails with an error that can't be recovered from,
+/// reconnecting according to the reconnect policy along the way.
+fn poll_until_closed(
+    proto: &Mutex<protocol::DiscordVoiceProtocol>,
+    loop_: &PyObject,
+    callbacks: &Callbacks,
+) -> error::ProtocolError {
+    loop {
+        let (result, events) = {
+            let mut guard = proto.lock();
+            let result = guard.poll();
+            let events = guard.take_events();
+            // Hand the lock over to any waiting thread before polling again
+            MutexGuard::unlock_fair(guard);
+            (result, events)
+        };
+        callbacks.dispatch(loop_, events);
+        if let Err(e) = result {
+            return match e {
+                error::ProtocolError::Closed(code, reason) if code_can_be_handled(code) => {
+                    match reconnect_with_policy(proto, loop_, callbacks, code, reason) {
+                        Ok(()) => continue,
+                        Err(e) => e,
+                    }
+                }
+                e => e,
+            };
+        }
+    }
+}
+
+fn reconnect_policy_from_args(
+    base_delay: f64,
+    max_delay: f64,
+    multiplier: f64,
+    max_attempts: u32,
+) -> PyResult<protocol::ReconnectPolicy> {
+    if !(base_delay > 0.0 && base_delay.is_finite()) {
+        return Err(pyo3::exceptions::PyValueError::new_err(
+            "base_delay must be a positive number",
+        ));
+    }
+
+    if !(max_delay >= base_delay && max_delay.is_finite()) {
+        return Err(pyo3::exceptions::PyValueError::new_err(
+            "max_delay must be greater than or equal to base_delay",
+        ));
+    }
+
+    if !(multiplier >= 1.0 && multiplier.is_finite()) {
+        return Err(pyo3::exceptions::PyValueError::new_err(
+            "multiplier must be at least 1.0",
+        ));
+    }
+
+    Ok(protocol::ReconnectPolicy {
+        base_delay: Duration::from_secs_f64(base_delay),
+        max_delay: Duration::from_secs_f64(max_delay),
+        multiplier,
+        max_attempts,
+    })
+}
+
+/// An audio source backed by a Python iterable that yields already
+/// encoded Opus frames as bytes, one frame per item.
+struct PyOpusSource {
+    iterator: PyObject,
+    // Set when a frame can't be sent, which ends the source
+    error: Option<error::ProtocolError>,
+}
+
+impl player::AudioSource for PyOpusSource {
+    fn get_type(&self) -> player::AudioType {
+        player::AudioType::Opus
+    }
+
+    fn kind(&self) -> &'static str {
+        "opus"
+    }
+
+    fn read_opus_frame(&mut self, buffer: &mut [u8]) -> Option<usize> {
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+        let item = match self.iterator.call_method0(py, "__next__") {
+            Ok(item) => item,
+            Err(e) if e.is_instance::<pyo3::exceptions::PyStopIteration>(py) => return None,
+            Err(e) => {
+                e.print(py);
+                return None;
+            }
+        };
+
+        let frame: &[u8] = match item.extract(py) {
+            Ok(frame) => frame,
+            Err(e) => {
+                e.print(py);
+                return None;
+            }
+        };
+
+        if frame.len() > buffer.len() {
+            self.error = Some(error::custom_error(&format!(
+                "opus frame is {} bytes, more than the {} that can be sent",
+                frame.len(),
+                buffer.len()
+            )));
+            return None;
+        }
+
+        buffer[..frame.len()].copy_from_slice(frame);
+        Some(frame.len())
+    }
+
+    fn take_error(&mut self) -> Option<error::ProtocolError> {
+        self.error.take()
+    }
+}
+
+/// Reads from a Python file-like object by calling its read method,
+/// which is expected to return bytes and an empty bytes object at EOF.
+struct PyReader {
+    file: PyObject,
+}
+
+impl std::io::Read for PyReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+        let data = match self.file.call_method1(py, "read", (buf.len(),)) {
+            Ok(data) => data,
+            Err(e) => {
+                e.print(py);
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    "could not read from the file object",
+                ));
+            }
+        };
+
+        let data: &[u8] = match data.extract(py) {
+            Ok(data) => data,
+            Err(e) => {
+                e.print(py);
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "file object did not return bytes",
+                ));
+            }
+        };
+
+        // A read that returns more than asked for is cut short rather than buffered
+        let size = data.len().min(buf.len());
+        buf[..size].copy_from_slice(&data[..size]);
+        Ok(size)
+    }
+}
+
 #[pyclass]
 struct VoiceConnection {
     protocol: Arc<Mutex<protocol::DiscordVoiceProtocol>>,
+    state: Arc<state::PlayingState>,
     player: Option<player::AudioPlayer>,
+    receiver: Option<receiver::AudioReceiver>,
+    // Sends raw Opus frames while there's no player to send them
+    raw_sender: Option<player::RawOpusSender>,
+    bitrate: Arc<AtomicI32>,
+    muted: Arc<AtomicBool>,
+    pcm_tap: player::PcmTap,
+    encoder_options: player::EncoderOptions,
+    source_timeout: Duration,
+    packet_loss: player::PacketLoss,
+    loop_: PyObject,
+    /// Set with the error the poll loop ended with, if it was started by connect_and_run
+    closed: Option<PyObject>,
+    callbacks: Arc<Callbacks>,
+}
+
+impl VoiceConnection {
+    fn new(
+        loop_: PyObject,
+        protocol: protocol::DiscordVoiceProtocol,
+        closed: Option<PyObject>,
+    ) -> Self {
+        Self {
+            loop_,
+            state: protocol.clone_state(),
+            protocol: Arc::new(Mutex::new(protocol)),
+            player: None,
+            receiver: None,
+            raw_sender: None,
+            bitrate: Arc::new(AtomicI32::new(player::DEFAULT_BITRATE)),
+            muted: Arc::new(AtomicBool::new(false)),
+            pcm_tap: Arc::new(Mutex::new(None)),
+            encoder_options: player::EncoderOptions::default(),
+            source_timeout: player::DEFAULT_READ_TIMEOUT,
+            packet_loss: Arc::new(Mutex::new(None)),
+            closed,
+            callbacks: Arc::new(Callbacks::default()),
+        }
+    }
 }
 
 #[pymethods]
 impl VoiceConnection {
+    /// Runs ffprobe on the input (anything play accepts) in a background thread.
+    /// The returned future resolves to a dict with the duration in seconds, the
+    /// bitrate, the audio codec and the title and artist tags, any of which can
+    /// be None. It fails with FFmpegNotFound if ffprobe isn't installed and with
+    /// ConnectionError if it can't be run for any other reason.
+    #[staticmethod]
+    #[text_signature = "(loop, input, /)"]
+    fn probe(py: Python, loop_: PyObject, input: String) -> PyResult<PyObject> {
+        let (future, result): (PyObject, PyObject) = {
+            let fut: PyObject = loop_.call_method0(py, "create_future")?.into();
+            (fut.clone_ref(py), fut)
+        };
+
+        thread::spawn(move || {
+            let info = player::probe(input.as_str());
+            let gil = Python::acquire_gil();
+            let py = gil.python();
+            let result = info
+                .map_err(PyErr::from)
+                .and_then(|info| probe_info_to_dict(py, info));
+            let _ = match result {
+                Err(e) => set_exception(py, loop_, future, e),
+                Ok(dict) => set_result(py, loop_, future, dict),
+            };
+        });
+        Ok(result)
+    }
+
     #[text_signature = "(loop, /)"]
     fn run(&mut self, py: Python, loop_: PyObject) -> PyResult<PyObject> {
         let (future, result): (PyObject, PyObject) = {
@@ -65,34 +502,121 @@ impl VoiceConnection {
         };
 
         let proto = Arc::clone(&self.protocol);
+        let callbacks = Arc::clone(&self.callbacks);
         thread::spawn(move || {
-            loop {
-                let result = {
-                    // TODO: consider not using locks?
-                    let mut guard = proto.lock();
-                    guard.poll()
-                };
-                if let Err(e) = result {
-                    let gil = Python::acquire_gil();
-                    let py = gil.python();
-                    match e {
-                        error::ProtocolError::Closed(code) if code_can_be_handled(code) => {
-                            let _ = set_result(py, loop_, future, py.None());
-                            break;
-                        }
-                        _ => {
-                            let _ = set_exception(py, loop_, future, PyErr::from(e));
-                            break;
-                        }
-                    }
-                }
-            }
+            let e = poll_until_closed(&proto, &loop_, &callbacks);
+            let gil = Python::acquire_gil();
+            let py = gil.python();
+            let _ = set_exception(py, loop_, future, PyErr::from(e));
         });
         Ok(result)
     }
 
-    fn disconnect(&mut self) -> PyResult<()> {
+    /// Polls the connection once without blocking, which is an alternative to run
+    /// for driving many connections from the event loop rather than a thread each.
+    /// It has to be called often enough to keep heartbeating, e.g. every 100ms,
+    /// and mustn't be combined with run. Returns whether a message was handled.
+    /// Errors are raised as they are, so reconnecting is up to the caller.
+    fn poll_once(&mut self) -> PyResult<bool> {
+        let (result, events) = {
+            let mut proto = self.protocol.lock();
+            (proto.poll_once(), proto.take_events())
+        };
+        self.callbacks.dispatch(&self.loop_, events);
+        Ok(result?)
+    }
+
+    /// Sets the callback that's called with the user ID of each user that leaves
+    /// the channel, after their SSRC mapping and speaking state have been removed.
+    /// It's scheduled on the event loop. Pass None to remove it.
+    #[text_signature = "(callback, /)"]
+    fn on_client_disconnect(&mut self, callback: Option<PyObject>) {
+        *self.callbacks.client_disconnect.lock() = callback;
+    }
+
+    /// Sets the callback that's called with every event on the connection, as a dict
+    /// with a "type" key. It's scheduled on the event loop. Pass None to remove it.
+    /// The types and their other keys are:
+    ///   connected: resumed
+    ///   reconnecting: code, attempt, delay
+    ///   speaking: user_id, ssrc, speaking
+    ///   latency: latency
+    ///   client_connect: user_id, ssrc
+    ///   client_disconnect: user_id
+    ///   heartbeat_mismatch: expected, received
+    ///   track_finished: index, reason, error
+    #[text_signature = "(callback, /)"]
+    fn on_event(&mut self, callback: Option<PyObject>) {
+        *self.callbacks.event.lock() = callback;
+    }
+
+    /// A future that fails with the error the connection ended with, for
+    /// connections made by VoiceConnector.connect_and_run. None otherwise.
+    #[getter]
+    fn closed(&self, py: Python) -> Option<PyObject> {
+        self.closed.as_ref().map(|closed| closed.clone_ref(py))
+    }
+
+    /// Reconnects with new credentials from a voice server update while the
+    /// current connection is still up. This blocks until the session is resumed
+    /// (or identified again), so it should be run in an executor.
+    /// The player waits while the connection is down as it would for any other
+    /// reconnect and carries on afterwards, staying paused if it was paused,
+    /// which the playing state keeps track of across the disconnect.
+    #[text_signature = "(token, endpoint, session_id, /)"]
+    fn reconnect(
+        &mut self,
+        py: Python,
+        token: String,
+        endpoint: String,
+        session_id: String,
+    ) -> PyResult<()> {
+        let protocol = Arc::clone(&self.protocol);
+        py.allow_threads(move || protocol.lock().reconnect_with(endpoint, token, session_id))?;
+        Ok(())
+    }
+
+    /// Stores new credentials from a voice server update for the next reconnect,
+    /// without interrupting the current connection. Use reconnect instead to
+    /// switch to them straight away.
+    #[text_signature = "(token, endpoint, session_id, /)"]
+    fn update_credentials(&mut self, token: String, endpoint: String, session_id: String) {
+        let mut proto = self.protocol.lock();
+        proto.update_credentials(endpoint, token, session_id);
+    }
+
+    /// Tears the connection down in order: the player is stopped and its thread joined,
+    /// which kills any FFmpeg process, speaking is turned off and only then is the
+    /// websocket closed. This waits up to timeout seconds for the player thread, so it
+    /// should be run in an executor.
+    #[args(timeout = "5.0")]
+    #[text_signature = "(timeout=5.0)"]
+    fn disconnect(&mut self, py: Python, timeout: f64) -> PyResult<()> {
+        if !timeout.is_finite() || timeout < 0.0 {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "timeout must be a non-negative number of seconds",
+            ));
+        }
+
+        if let Some(receiver) = &self.receiver {
+            receiver.stop();
+        }
+        self.raw_sender = None;
+        *self.pcm_tap.lock() = None;
+        // The player would otherwise wait for a reconnect after the websocket is closed
+        self.stop();
+        self.state.terminate();
+        if let Some(mut player) = self.player.take() {
+            let timeout = Duration::from_secs_f64(timeout);
+            // A player thread that doesn't exit in time is left to finish on its own
+            py.allow_threads(move || player.shutdown(timeout));
+        }
+
         let mut guard = self.protocol.lock();
+        // The player turns speaking off as it exits, this covers it not exiting in time.
+        // Both are queued and written by close before the close frame.
+        let sender = guard.clone_sender();
+        let _ = sender.speaking(payloads::SpeakingFlags::off(), false);
         guard.close(1000)?;
         Ok(())
     }
@@ -103,24 +627,656 @@ impl VoiceConnection {
         }
     }
 
-    fn play(&mut self, input: String) -> PyResult<()> {
-        if let Some(player) = &self.player {
-            player.stop();
+    /// Stops playback and blocks until the player thread has exited and its
+    /// sources (e.g. FFmpeg) have been cleaned up, waiting up to timeout seconds.
+    /// Returns whether it finished in time. This calls the after callback,
+    /// so it should be run in an executor.
+    #[args(timeout = "5.0")]
+    #[text_signature = "(timeout=5.0)"]
+    fn shutdown(&mut self, py: Python, timeout: f64) -> PyResult<bool> {
+        if !timeout.is_finite() || timeout < 0.0 {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "timeout must be a non-negative number of seconds",
+            ));
         }
 
-        let source = Box::new(player::FFmpegPCMAudio::new(input.as_str())?);
-        let player = player::AudioPlayer::new(
-            |error| {
-                println!("Audio Player Error: {:?}", error);
+        let mut player = match self.player.take() {
+            Some(player) => player,
+            None => return Ok(true),
+        };
+        let timeout = Duration::from_secs_f64(timeout);
+        Ok(py.allow_threads(move || player.shutdown(timeout)))
+    }
+
+    /// Plays the input through FFmpeg.
+    /// If given, after is called on the event loop with the error (or None), the
+    /// reason playback ended, which is one of "finished", "stopped", "skipped" or "error",
+    /// and the index of the track. The played input is track 0 and anything enqueued
+    /// afterwards is numbered in order, with after being called as each one ends.
+    /// speaking is the raw speaking flags sent while playing, e.g. 2 (soundshare)
+    /// when relaying screen share audio.
+    /// send_policy is what happens when the network can't keep up: "drop" drops the
+    /// packet, which suits live audio, while "block" retries it for up to a frame
+    /// before dropping it, for pre-rendered audio where every frame matters.
+    /// These apply to all of the play methods.
+    /// If downmix is true the audio is mixed down to mono, which is still sent as
+    /// stereo with both channels being identical.
+    /// If limiter is given, a peak limiter keeps the audio below that fraction of
+    /// full scale (between 0 and 1), e.g. 0.9.
+    /// prebuffer is the number of frames FFmpeg has to have read ahead before the
+    /// first one is sent, which avoids stutter at the start of network inputs.
+    #[allow(clippy::too_many_arguments)]
+    #[args(
+        after = "None",
+        speaking = "1",
+        send_policy = "\"drop\"",
+        downmix = "false",
+        limiter = "None",
+        prebuffer = "5"
+    )]
+    #[text_signature = "(input, after=None, speaking=1, send_policy='drop', downmix=False, limiter=None, prebuffer=5)"]
+    fn play(
+        &mut self,
+        py: Python,
+        input: String,
+        after: Option<PyObject>,
+        speaking: u8,
+        send_policy: &str,
+        downmix: bool,
+        limiter: Option<f32>,
+        prebuffer: usize,
+    ) -> PyResult<()> {
+        let source = self.ffmpeg_source(input.as_str(), &[], &[], downmix, limiter, prebuffer)?;
+        self.play_source(py, source, after, speaking, send_policy)
+    }
+
+    /// Plays 16-bit stereo 48000Hz little-endian PCM from memory
+    #[args(after = "None", speaking = "1", send_policy = "\"drop\"")]
+    #[text_signature = "(data, after=None, speaking=1, send_policy='drop')"]
+    fn play_pcm(
+        &mut self,
+        py: Python,
+        data: &PyBytes,
+        after: Option<PyObject>,
+        speaking: u8,
+        send_policy: &str,
+    ) -> PyResult<()> {
+        let source = Box::new(player::BytesPCMAudio::new(data.as_bytes().to_vec()));
+        self.play_source(py, source, after, speaking, send_policy)
+    }
+
+    /// Plays already encoded Opus audio from an iterable of bytes,
+    /// where each item is a single 20ms Opus frame. No encoding is done.
+    #[args(after = "None", speaking = "1", send_policy = "\"drop\"")]
+    #[text_signature = "(source, after=None, speaking=1, send_policy='drop')"]
+    fn play_opus(
+        &mut self,
+        py: Python,
+        source: PyObject,
+        after: Option<PyObject>,
+        speaking: u8,
+        send_policy: &str,
+    ) -> PyResult<()> {
+        let iterator = source.as_ref(py).iter()?;
+        let source = Box::new(PyOpusSource {
+            iterator: iterator.to_object(py),
+            error: None,
+        });
+        self.play_source(py, source, after, speaking, send_policy)
+    }
+
+    /// Plays a pre-encoded DCA file
+    #[args(after = "None", speaking = "1", send_policy = "\"drop\"")]
+    #[text_signature = "(path, after=None, speaking=1, send_policy='drop')"]
+    fn play_dca(
+        &mut self,
+        py: Python,
+        path: String,
+        after: Option<PyObject>,
+        speaking: u8,
+        send_policy: &str,
+    ) -> PyResult<()> {
+        let source = Box::new(player::DCAAudio::new(path.as_str())?);
+        self.play_source(py, source, after, speaking, send_policy)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[args(
+        after = "None",
+        speaking = "1",
+        send_policy = "\"drop\"",
+        downmix = "false",
+        limiter = "None",
+        prebuffer = "5"
+    )]
+    #[text_signature = "(input, before_options, options, after=None, speaking=1, send_policy='drop', downmix=False, limiter=None, prebuffer=5)"]
+    fn play_with_options(
+        &mut self,
+        py: Python,
+        input: String,
+        before_options: Vec<String>,
+        options: Vec<String>,
+        after: Option<PyObject>,
+        speaking: u8,
+        send_policy: &str,
+        downmix: bool,
+        limiter: Option<f32>,
+        prebuffer: usize,
+    ) -> PyResult<()> {
+        let source = self.ffmpeg_source(
+            input.as_str(),
+            &before_options,
+            &options,
+            downmix,
+            limiter,
+            prebuffer,
+        )?;
+        self.play_source(py, source, after, speaking, send_policy)
+    }
+
+    /// Plays audio that FFmpeg reads from its stdin, which is fed from the given
+    /// file-like object on a background thread until its read method returns
+    /// nothing. The rest of the arguments are the same as play_with_options'.
+    /// Piped audio can't be seeked.
+    #[allow(clippy::too_many_arguments)]
+    #[args(
+        after = "None",
+        speaking = "1",
+        send_policy = "\"drop\"",
+        downmix = "false",
+        limiter = "None",
+        prebuffer = "5"
+    )]
+    #[text_signature = "(file, before_options, options, after=None, speaking=1, send_policy='drop', downmix=False, limiter=None, prebuffer=5)"]
+    fn play_pipe(
+        &mut self,
+        py: Python,
+        file: PyObject,
+        before_options: Vec<String>,
+        options: Vec<String>,
+        after: Option<PyObject>,
+        speaking: u8,
+        send_policy: &str,
+        downmix: bool,
+        limiter: Option<f32>,
+        prebuffer: usize,
+    ) -> PyResult<()> {
+        check_limiter(limiter)?;
+        let reader = PyReader { file };
+        let ffmpeg = player::FFmpegPCMAudio::from_reader(
+            reader,
+            &before_options,
+            &options,
+            self.encoder_options.channel_count(),
+            self.encoder_options.frame_length,
+        )?;
+        let source = self.wrap_ffmpeg(ffmpeg, downmix, limiter, prebuffer);
+        self.play_source(py, source, after, speaking, send_policy)
+    }
+
+    /// Starts receiving audio from the other users in the channel.
+    /// The callback is called from a background thread with the SSRC,
+    /// the user ID (or None if unknown) and the decoded PCM as bytes.
+    /// If receiving stopped because of an error, that error is raised here
+    /// (or by stop_listening) and listening has to be started again.
+    #[text_signature = "(callback, /)"]
+    fn listen(&mut self, callback: PyObject) -> PyResult<()> {
+        if let Some(receiver) = &self.receiver {
+            receiver.stop();
+            if let Some(e) = receiver.take_error() {
+                return Err(e.into());
+            }
+        }
+
+        let receiver = receiver::AudioReceiver::new(
+            move |ssrc, user_id, pcm| {
+                let gil = Python::acquire_gil();
+                let py = gil.python();
+                let bytes = player::encode_pcm_le(pcm);
+                if let Err(e) = callback.call1(py, (ssrc, user_id, PyBytes::new(py, &bytes))) {
+                    e.print(py);
+                }
             },
             Arc::clone(&self.protocol),
-            Arc::new(Mutex::new(source)),
         );
 
-        self.player = Some(player);
+        self.receiver = Some(receiver);
+        Ok(())
+    }
+
+    /// Calls the callback from a background thread with each PCM frame that's played
+    /// (16-bit stereo 48000Hz, 3840 bytes for 20ms frames) as bytes, before it's encoded.
+    /// Opus sources have no PCM so they aren't tapped. The frames are queued for the
+    /// callback rather than passed straight to it, and once the queue is full new
+    /// frames are dropped, so a slow callback misses frames instead of holding up the
+    /// audio. Pass None to remove the tap.
+    #[text_signature = "(callback, /)"]
+    fn pcm_tap(&self, callback: Option<PyObject>) {
+        let callback = match callback {
+            Some(callback) => callback,
+            None => {
+                *self.pcm_tap.lock() = None;
+                return;
+            }
+        };
+
+        let (sender, frames) = crossbeam_channel::bounded::<Vec<u8>>(player::PCM_TAP_CAPACITY);
+        thread::spawn(move || {
+            // This ends once the tap is replaced or removed
+            for frame in frames.iter() {
+                let gil = Python::acquire_gil();
+                let py = gil.python();
+                if let Err(e) = callback.call1(py, (PyBytes::new(py, &frame),)) {
+                    e.print(py);
+                }
+            }
+        });
+        *self.pcm_tap.lock() = Some(sender);
+    }
+
+    /// Stops receiving audio, raising the error that stopped it if there was one
+    fn stop_listening(&mut self) -> PyResult<()> {
+        if let Some(receiver) = &self.receiver {
+            receiver.stop();
+            if let Some(e) = receiver.take_error() {
+                return Err(e.into());
+            }
+        }
+        Ok(())
+    }
+
+    fn is_listening(&self) -> bool {
+        if let Some(receiver) = &self.receiver {
+            receiver.is_listening()
+        } else {
+            false
+        }
+    }
+
+    /// Sets how resumable disconnects are retried before giving up with a ReconnectError.
+    /// Delays are in seconds.
+    #[text_signature = "(base_delay, max_delay, multiplier, max_attempts, /)"]
+    fn set_reconnect_policy(
+        &mut self,
+        base_delay: f64,
+        max_delay: f64,
+        multiplier: f64,
+        max_attempts: u32,
+    ) -> PyResult<()> {
+        let policy = reconnect_policy_from_args(base_delay, max_delay, multiplier, max_attempts)?;
+        let mut proto = self.protocol.lock();
+        proto.reconnect_policy = policy;
+        Ok(())
+    }
+
+    /// For debugging only: drops drop_pct percent of the outgoing packets and sends
+    /// reorder_pct percent of them after the packet following them, to test how
+    /// receivers cope with a bad network. The same seed drops the same packets.
+    /// Passing 0 for both turns the simulation off, which is the default.
+    #[args(seed = "0")]
+    #[text_signature = "(drop_pct, reorder_pct, seed=0)"]
+    fn set_packet_loss_simulation(
+        &mut self,
+        drop_pct: f64,
+        reorder_pct: f64,
+        seed: u64,
+    ) -> PyResult<()> {
+        let valid = |pct: f64| (0.0..=100.0).contains(&pct);
+        if !valid(drop_pct) || !valid(reorder_pct) || drop_pct + reorder_pct > 100.0 {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "percentages must be between 0 and 100 and add up to at most 100",
+            ));
+        }
+
+        let mut simulation = self.packet_loss.lock();
+        if drop_pct <= 0.0 && reorder_pct <= 0.0 {
+            *simulation = None;
+        } else {
+            *simulation = Some(player::PacketLossSimulation::new(
+                drop_pct / 100.0,
+                reorder_pct / 100.0,
+                seed,
+            ));
+        }
+        Ok(())
+    }
+
+    /// Sets the bitrate (in bits per second) used to encode audio.
+    /// If audio is currently playing then it is applied without interrupting playback.
+    #[text_signature = "(bits_per_second, /)"]
+    fn set_bitrate(&mut self, bits_per_second: i32) -> PyResult<()> {
+        if bits_per_second < player::MIN_BITRATE || bits_per_second > player::MAX_BITRATE {
+            return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "bitrate must be between {} and {} bits per second",
+                player::MIN_BITRATE,
+                player::MAX_BITRATE
+            )));
+        }
+
+        self.bitrate.store(bits_per_second, Ordering::Relaxed);
+        Ok(())
+    }
+
+    #[getter]
+    fn bitrate(&self) -> i32 {
+        self.bitrate.load(Ordering::Relaxed)
+    }
+
+    /// Sets how many seconds to wait for FFmpeg to produce audio before the
+    /// track is ended with an error. Applies to tracks started after this call.
+    #[text_signature = "(seconds, /)"]
+    fn set_source_timeout(&mut self, seconds: f64) -> PyResult<()> {
+        if !seconds.is_finite() || seconds <= 0.0 {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "timeout must be a positive number of seconds",
+            ));
+        }
+
+        self.source_timeout = Duration::from_secs_f64(seconds);
         Ok(())
     }
 
+    /// Sets how many seconds sending a single packet may block before it's given up on
+    /// and handled by the send policy of play. Applies immediately.
+    #[text_signature = "(seconds, /)"]
+    fn set_send_timeout(&mut self, seconds: f64) -> PyResult<()> {
+        if !seconds.is_finite() || seconds <= 0.0 {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "timeout must be a positive number of seconds",
+            ));
+        }
+
+        let mut proto = self.protocol.lock();
+        proto.set_send_timeout(Duration::from_secs_f64(seconds))?;
+        Ok(())
+    }
+
+    /// Sets the Opus encoder tuning options used by the next call to play.
+    /// signal is one of "auto", "voice" or "music" and bandwidth is one of
+    /// "auto", "narrow", "medium", "wide", "superwide" or "full".
+    /// channels is either 1 (mono) or 2 (stereo).
+    /// If silence_threshold is given, PCM frames with no sample louder than it
+    /// aren't encoded. A few Opus silence frames are sent at the start of the
+    /// silence and nothing after that until the audio is louder again.
+    /// frame_length is the length of each Opus frame in milliseconds and must be
+    /// 10, 20, 40 or 60. Opus audio can only be played with 20ms frames.
+    /// bitrate_mode is one of "vbr", "constrained" or "cbr". Unconstrained VBR can
+    /// go well over the bitrate for short bursts, constrained VBR (the Opus default)
+    /// stays under it over a short window and CBR sends every frame at the same size,
+    /// so it uses exactly the bitrate even when the audio doesn't need it.
+    #[allow(clippy::too_many_arguments)]
+    #[args(
+        fec = "true",
+        packet_loss_percentage = "15",
+        signal = "\"auto\"",
+        bandwidth = "\"full\"",
+        channels = "2",
+        silence_threshold = "None",
+        frame_length = "20",
+        bitrate_mode = "\"constrained\""
+    )]
+    #[text_signature = "(fec=True, packet_loss_percentage=15, signal='auto', bandwidth='full', channels=2, silence_threshold=None, frame_length=20, bitrate_mode='constrained')"]
+    fn set_encoder_options(
+        &mut self,
+        fec: bool,
+        packet_loss_percentage: u8,
+        signal: &str,
+        bandwidth: &str,
+        channels: u8,
+        silence_threshold: Option<u16>,
+        frame_length: u16,
+        bitrate_mode: &str,
+    ) -> PyResult<()> {
+        if packet_loss_percentage > 100 {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "packet loss percentage must be between 0 and 100",
+            ));
+        }
+
+        let signal = player::parse_signal(signal)
+            .ok_or_else(|| pyo3::exceptions::PyValueError::new_err("unknown signal type"))?;
+        let bandwidth = player::parse_bandwidth(bandwidth)
+            .ok_or_else(|| pyo3::exceptions::PyValueError::new_err("unknown bandwidth"))?;
+        let bitrate_mode = player::parse_bitrate_mode(bitrate_mode)
+            .ok_or_else(|| pyo3::exceptions::PyValueError::new_err("unknown bitrate mode"))?;
+        let channels = match channels {
+            1 => audiopus::Channels::Mono,
+            2 => audiopus::Channels::Stereo,
+            _ => {
+                return Err(pyo3::exceptions::PyValueError::new_err(
+                    "channels must be either 1 or 2",
+                ))
+            }
+        };
+        if !player::FRAME_LENGTHS.contains(&frame_length) {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "frame length must be 10, 20, 40 or 60 ms",
+            ));
+        }
+
+        self.encoder_options = player::EncoderOptions {
+            fec,
+            packet_loss_perc: packet_loss_percentage,
+            signal,
+            bandwidth,
+            channels,
+            silence_threshold,
+            frame_length,
+            bitrate_mode,
+        };
+        Ok(())
+    }
+
+    /// Seeks the currently playing source to the given position in seconds.
+    /// Note that seeking resets the inband FEC decoder on the receiver side.
+    #[text_signature = "(seconds, /)"]
+    fn seek(&mut self, seconds: f64) -> PyResult<()> {
+        if seconds < 0.0 || !seconds.is_finite() {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "seek position must be a non-negative number",
+            ));
+        }
+
+        match &self.player {
+            Some(player) => {
+                if player.seek(seconds)? {
+                    Ok(())
+                } else {
+                    Err(pyo3::exceptions::PyValueError::new_err(
+                        "the current audio source does not support seeking",
+                    ))
+                }
+            }
+            None => Err(pyo3::exceptions::PyValueError::new_err(
+                "not playing any audio",
+            )),
+        }
+    }
+
+    /// Queues the input (played through FFmpeg) to play after the current track
+    /// without stopping the speaking state in between.
+    /// If nothing is playing then the input is played right away.
+    #[text_signature = "(input, /)"]
+    fn enqueue(&mut self, py: Python, input: String) -> PyResult<()> {
+        let prebuffer = player::DEFAULT_PREBUFFER_FRAMES;
+        let source = self.ffmpeg_source(input.as_str(), &[], &[], false, None, prebuffer)?;
+        let source = match &self.player {
+            Some(player) => player.enqueue(source),
+            None => Some(source),
+        };
+
+        match source {
+            Some(source) => self.play_source(py, source, None, 1, "drop"),
+            None => Ok(()),
+        }
+    }
+
+    /// Sends a single already encoded Opus frame with the negotiated encryption.
+    /// While a player is running the frame takes the place of its next frame,
+    /// so the RTP sequence numbers don't collide, otherwise it's sent right away.
+    #[text_signature = "(frame, /)"]
+    fn send_raw_opus(&mut self, frame: &PyBytes) -> PyResult<()> {
+        let frame = frame.as_bytes();
+        if frame.is_empty() || frame.len() > player::MAX_OPUS_FRAME_SIZE {
+            return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                "opus frame must be between 1 and {} bytes",
+                player::MAX_OPUS_FRAME_SIZE
+            )));
+        }
+        if self.state.is_disconnected() {
+            return Err(pyo3::exceptions::PyRuntimeError::new_err(
+                "voice connection not ready",
+            ));
+        }
+
+        if let Some(player) = &self.player {
+            if player.send_raw_opus(frame.to_vec()).is_ok() {
+                return Ok(());
+            }
+        }
+
+        let proto = self.protocol.lock();
+        if self.raw_sender.is_none() {
+            self.raw_sender = Some(player::RawOpusSender::new(&*proto, &self.encoder_options)?);
+        }
+        if let Some(sender) = &mut self.raw_sender {
+            sender.send(&*proto, &self.encoder_options, frame)?;
+        }
+        Ok(())
+    }
+
+    /// Sends silence in place of the audio without pausing, so the track keeps
+    /// advancing while it can't be heard. This carries over to later tracks.
+    fn mute(&self) {
+        self.muted.store(true, Ordering::Relaxed);
+    }
+
+    fn unmute(&self) {
+        self.muted.store(false, Ordering::Relaxed);
+    }
+
+    #[getter]
+    fn is_muted(&self) -> bool {
+        self.muted.load(Ordering::Relaxed)
+    }
+
+    /// Ends the current track and plays the next queued one, if any
+    fn skip(&mut self) {
+        if let Some(player) = &self.player {
+            player.skip();
+        }
+    }
+
+    /// Restarts the current track whenever it ends instead of finishing, until
+    /// looping is disabled or the player is stopped. Sources that can't be
+    /// restarted, such as piped input or Opus from a Python iterator, raise.
+    #[text_signature = "(enabled, /)"]
+    fn set_loop(&mut self, enabled: bool) -> PyResult<()> {
+        match &self.player {
+            Some(player) if enabled && !player.can_restart() => {
+                Err(pyo3::exceptions::PyValueError::new_err(
+                    "the current audio source can't be restarted, so it can't be looped",
+                ))
+            }
+            Some(player) => {
+                player.set_loop(enabled)?;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    #[getter]
+    fn looping(&self) -> bool {
+        match &self.player {
+            Some(player) => player.is_looping(),
+            None => false,
+        }
+    }
+
+    #[getter]
+    fn queue_length(&self) -> usize {
+        match &self.player {
+            Some(player) => player.queue_len(),
+            None => 0,
+        }
+    }
+
+    /// Fades from the current track into the input (played through FFmpeg) over
+    /// the given duration without interrupting playback. If nothing is playing
+    /// then the input is played normally. The current track's after callback is
+    /// called once the new input ends.
+    #[text_signature = "(input, duration_ms, /)"]
+    fn crossfade_to(&mut self, py: Python, input: String, duration_ms: u32) -> PyResult<()> {
+        // Waiting for a prebuffer here would hold up the track being faded out
+        let source = self.ffmpeg_source(input.as_str(), &[], &[], false, None, 0)?;
+        match &self.player {
+            Some(player) if !player.is_finished() => {
+                let frames = duration_ms / player.frame_length() as u32;
+                player.crossfade_to(source, frames);
+                Ok(())
+            }
+            _ => self.play_source(py, source, None, 1, "drop"),
+        }
+    }
+
+    /// Switches the current track to the input (played through FFmpeg) without
+    /// stopping the player, so there's no speaking blip and the stream carries on
+    /// seamlessly. If nothing is playing then the input is played normally.
+    /// The current track's after callback is called once the new input ends.
+    #[text_signature = "(input, /)"]
+    fn swap_source(&mut self, py: Python, input: String) -> PyResult<()> {
+        let prebuffer = player::DEFAULT_PREBUFFER_FRAMES;
+        let source = self.ffmpeg_source(input.as_str(), &[], &[], false, None, prebuffer)?;
+        let source = match &self.player {
+            Some(player) => match player.swap_source(source) {
+                Ok(()) => return Ok(()),
+                Err(source) => source,
+            },
+            None => source,
+        };
+        self.play_source(py, source, None, 1, "drop")
+    }
+
+    /// Mixes the input into the audio that's playing rather than interrupting it,
+    /// e.g. for sound effects over music. The input is either bytes of 16-bit stereo
+    /// 48000Hz PCM or anything FFmpeg can play, and gain scales its volume.
+    /// If nothing is playing then the input is played on its own, with anything
+    /// added afterwards being mixed with it.
+    #[args(gain = "1.0")]
+    #[text_signature = "(input, gain=1.0)"]
+    fn add_mix_source(&mut self, py: Python, input: &PyAny, gain: f32) -> PyResult<()> {
+        if !gain.is_finite() || gain < 0.0 {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "gain must be a non-negative number",
+            ));
+        }
+
+        let source: Box<dyn player::AudioSource> = match input.downcast::<PyBytes>() {
+            Ok(data) => Box::new(player::BytesPCMAudio::new(data.as_bytes().to_vec())),
+            Err(_) => {
+                let input: String = input.extract()?;
+                // Waiting for a prebuffer here would hold up what it's mixed into
+                self.ffmpeg_source(input.as_str(), &[], &[], false, None, 0)?
+            }
+        };
+
+        match &self.player {
+            Some(player) if !player.is_finished() => Ok(player.mix_in(source, gain)?),
+            _ => {
+                let mut mixer = player::Mixer::new();
+                mixer.add(source, gain)?;
+                self.play_source(py, Box::new(mixer), None, 1, "drop")
+            }
+        }
+    }
+
+    /// Whether the voice UDP path is ready, i.e. the session description has been
+    /// received and the connection hasn't been closed since
+    fn is_connected(&self) -> bool {
+        !self.state.is_disconnected()
+    }
+
     fn is_playing(&self) -> bool {
         if let Some(player) = &self.player {
             player.is_playing()
@@ -130,29 +1286,190 @@ impl VoiceConnection {
     }
 
     #[getter]
-    fn encryption_mode(&self) -> PyResult<String> {
-        let encryption = {
-            let proto = self.protocol.lock();
-            proto.encryption
-        };
-        Ok(encryption.into())
+    fn encryption_mode(&self) -> PyResult<String> {
+        let encryption = {
+            let proto = self.protocol.lock();
+            proto.encryption
+        };
+        Ok(encryption.into())
+    }
+
+    /// The encryption modes offered by the voice server, of which encryption_mode was chosen
+    #[getter]
+    fn available_encryption_modes(&self) -> Vec<String> {
+        let proto = self.protocol.lock();
+        proto.available_modes.clone()
+    }
+
+    #[getter]
+    fn secret_key(&self) -> PyResult<Vec<u8>> {
+        let secret_key = {
+            let proto = self.protocol.lock();
+            proto.secret_key
+        };
+        Ok(secret_key.into())
+    }
+
+    /// A dict describing the current source with its kind ("ffmpeg", "bytes", "opus"
+    /// or "dca"), whether it's seekable and its type ("pcm" or "opus").
+    /// None if nothing has been played.
+    #[getter]
+    fn source_info(&self, py: Python) -> PyResult<PyObject> {
+        let player = match &self.player {
+            Some(player) => player,
+            None => return Ok(py.None()),
+        };
+
+        let (kind, seekable, audio_type) = player.source_info();
+        let result = PyDict::new(py);
+        result.set_item("kind", kind)?;
+        result.set_item("seekable", seekable)?;
+        result.set_item("type", audio_type.as_str())?;
+        Ok(result.to_object(py))
+    }
+
+    /// A dict of where the player thread spends its time: the number of frames sent,
+    /// the average microseconds per frame spent encoding, encrypting and sleeping,
+    /// the number of frames that were late and the number that failed to encode.
+    /// Late frames going up means the host can't keep up. None if nothing has been played.
+    #[getter]
+    fn performance_stats(&self, py: Python) -> PyResult<PyObject> {
+        let player = match &self.player {
+            Some(player) => player,
+            None => return Ok(py.None()),
+        };
+
+        let stats = player.performance();
+        let result = PyDict::new(py);
+        result.set_item("frames", stats.frames.load(Ordering::Relaxed))?;
+        result.set_item("avg_encode_us", stats.average_micros(&stats.encode_nanos))?;
+        result.set_item("avg_encrypt_us", stats.average_micros(&stats.encrypt_nanos))?;
+        result.set_item("avg_sleep_us", stats.average_micros(&stats.sleep_nanos))?;
+        result.set_item("late_frames", stats.late_frames.load(Ordering::Relaxed))?;
+        result.set_item("encode_errors", stats.encode_errors.load(Ordering::Relaxed))?;
+        Ok(result.to_object(py))
+    }
+
+    /// A dict of the encoder settings in effect: bitrate, fec, packet_loss_percentage,
+    /// signal, bandwidth and bitrate_mode. While playing these are read back from the player's
+    /// encoder, otherwise they're what the next player's encoder will be set up with.
+    #[getter]
+    fn encoder_info(&self, py: Python) -> PyResult<PyObject> {
+        let live = match &self.player {
+            Some(player) => player.encoder_info(),
+            None => None,
+        };
+        let info = match live {
+            Some(info) => info,
+            None => self
+                .encoder_options
+                .info(self.bitrate.load(Ordering::Relaxed)),
+        };
+
+        let result = PyDict::new(py);
+        result.set_item("bitrate", info.bitrate)?;
+        result.set_item("fec", info.fec)?;
+        result.set_item("packet_loss_percentage", info.packet_loss_perc)?;
+        result.set_item("signal", player::signal_name(info.signal))?;
+        result.set_item("bandwidth", player::bandwidth_name(info.bandwidth))?;
+        result.set_item("bitrate_mode", player::bitrate_mode_name(info.bitrate_mode))?;
+        Ok(result.to_object(py))
+    }
+
+    /// How far into the current track playback is, in seconds
+    #[getter]
+    fn playback_position(&self) -> f64 {
+        match &self.player {
+            Some(player) => player.position(),
+            None => 0.0,
+        }
+    }
+
+    #[getter]
+    fn endpoint(&self) -> String {
+        let proto = self.protocol.lock();
+        proto.endpoint.clone()
+    }
+
+    #[getter]
+    fn endpoint_ip(&self) -> String {
+        let proto = self.protocol.lock();
+        proto.endpoint_ip.clone()
     }
 
     #[getter]
-    fn secret_key(&self) -> PyResult<Vec<u8>> {
-        let secret_key = {
-            let proto = self.protocol.lock();
-            proto.secret_key
-        };
-        Ok(secret_key.into())
+    fn port(&self) -> u16 {
+        let proto = self.protocol.lock();
+        proto.port
+    }
+
+    #[getter]
+    fn ssrc(&self) -> u32 {
+        let proto = self.protocol.lock();
+        proto.ssrc
     }
 
     fn send_playing(&self) -> PyResult<()> {
-        let mut proto = self.protocol.lock();
-        proto.speaking(payloads::SpeakingFlags::microphone())?;
+        let sender = self.protocol.lock().clone_sender();
+        sender.speaking(payloads::SpeakingFlags::microphone(), false)?;
+        Ok(())
+    }
+
+    /// Sends a speaking payload with the given flags.
+    /// Priority speaking lowers the volume of the other speakers in the channel.
+    /// The delay (in milliseconds) should be left at 0 unless relaying
+    /// continuous audio that is buffered elsewhere, which bots normally aren't.
+    #[args(
+        microphone = "true",
+        soundshare = "false",
+        priority = "false",
+        delay = "0"
+    )]
+    #[text_signature = "(microphone=True, soundshare=False, priority=False, delay=0)"]
+    fn send_speaking(
+        &self,
+        microphone: bool,
+        soundshare: bool,
+        priority: bool,
+        delay: u32,
+    ) -> PyResult<()> {
+        let mut flags = payloads::SpeakingFlags::off();
+        if microphone {
+            flags.toggle(payloads::SpeakingFlags::MICROPHONE);
+        }
+        if soundshare {
+            flags.toggle(payloads::SpeakingFlags::SOUNDSHARE);
+        }
+        if priority {
+            flags.toggle(payloads::SpeakingFlags::PRIORITY);
+        }
+
+        let sender = self.protocol.lock().clone_sender();
+        sender.speaking_with_delay(flags, delay, false)?;
+        Ok(())
+    }
+
+    /// Sends a speaking payload with the raw speaking flags straight away, e.g. 0 to
+    /// stop showing as speaking during a long pause while the player stays alive.
+    /// This doesn't change the flags the player sends when it starts or ends.
+    /// Nothing is sent if the flags are the same as the ones last sent.
+    #[text_signature = "(flags, /)"]
+    fn set_speaking(&self, flags: u8) -> PyResult<()> {
+        let sender = self.protocol.lock().clone_sender();
+        sender.speaking(payloads::SpeakingFlags::new(flags), false)?;
         Ok(())
     }
 
+    /// Returns a dict mapping user IDs to their most recent speaking flags
+    fn get_speaking_states<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let result = PyDict::new(py);
+        let proto = self.protocol.lock();
+        for (user_id, speaking) in proto.speaking_states.iter() {
+            result.set_item(user_id, speaking)?;
+        }
+        Ok(result)
+    }
+
     fn get_state<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
         let result = PyDict::new(py);
         let proto = self.protocol.lock();
@@ -160,7 +1477,16 @@ impl VoiceConnection {
         result.set_item("encryption_mode", Into::<String>::into(proto.encryption))?;
         result.set_item("endpoint", proto.endpoint.clone())?;
         result.set_item("endpoint_ip", proto.endpoint_ip.clone())?;
+        result.set_item("session_id", proto.session_id.clone())?;
+        result.set_item("server_id", proto.server_id())?;
+        result.set_item("user_id", proto.user_id().parse::<u64>().ok())?;
         result.set_item("port", proto.port)?;
+        let (discovered_ip, discovered_port) = match proto.discovered_address() {
+            Some((ip, port)) => (Some(ip), Some(port)),
+            None => (None, None),
+        };
+        result.set_item("discovered_ip", discovered_ip)?;
+        result.set_item("discovered_port", discovered_port)?;
         result.set_item("token", proto.token.clone())?;
         result.set_item("ssrc", proto.ssrc)?;
         result.set_item(
@@ -168,10 +1494,132 @@ impl VoiceConnection {
             proto.last_heartbeat.elapsed().as_secs_f32(),
         )?;
         result.set_item("player_connected", self.player.is_some())?;
+        result.set_item("playback_position", self.playback_position())?;
+        result.set_item("reconnect_attempts", proto.reconnect_attempts)?;
+        result.set_item("was_resumed", proto.was_resumed)?;
+        result.set_item(
+            "connected_since",
+            proto.connected_since().map(|d| d.as_secs_f64()),
+        )?;
+        result.set_item(
+            "handshake_duration",
+            proto.handshake_duration().map(|d| d.as_secs_f64()),
+        )?;
+        let (sent, dropped) = match &self.player {
+            Some(player) => player.packet_counts(),
+            None => (0, 0),
+        };
+        result.set_item("packets_sent", sent)?;
+        result.set_item("packets_dropped", dropped)?;
         Ok(result)
     }
 }
 
+impl VoiceConnection {
+    fn ffmpeg_source(
+        &self,
+        input: &str,
+        before_options: &[String],
+        options: &[String],
+        downmix: bool,
+        limiter: Option<f32>,
+        prebuffer: usize,
+    ) -> PyResult<Box<dyn player::AudioSource>> {
+        check_limiter(limiter)?;
+        let ffmpeg = player::FFmpegPCMAudio::with_format(
+            input,
+            before_options,
+            options,
+            self.encoder_options.channel_count(),
+            self.encoder_options.frame_length,
+        )?;
+        Ok(self.wrap_ffmpeg(ffmpeg, downmix, limiter, prebuffer))
+    }
+
+    /// Applies the connection's source timeout, the prebuffer and the optional
+    /// downmix and limiter
+    fn wrap_ffmpeg(
+        &self,
+        mut ffmpeg: player::FFmpegPCMAudio,
+        downmix: bool,
+        limiter: Option<f32>,
+        prebuffer: usize,
+    ) -> Box<dyn player::AudioSource> {
+        ffmpeg.set_read_timeout(self.source_timeout);
+        ffmpeg.set_prebuffer(prebuffer);
+        let mut source: Box<dyn player::AudioSource> = Box::new(ffmpeg);
+        // FFmpeg already outputs mono when the encoder is configured for it
+        if downmix && self.encoder_options.channel_count() == player::CHANNELS {
+            source = Box::new(player::DownmixMono::new(source));
+        }
+        if let Some(threshold) = limiter {
+            source = Box::new(player::Limiter::new(source, threshold));
+        }
+        source
+    }
+
+    fn play_source(
+        &mut self,
+        py: Python,
+        source: Box<dyn player::AudioSource>,
+        after: Option<PyObject>,
+        speaking: u8,
+        send_policy: &str,
+    ) -> PyResult<()> {
+        let send_policy = player::parse_send_policy(send_policy)
+            .ok_or_else(|| pyo3::exceptions::PyValueError::new_err("unknown send policy"))?;
+        if self.state.is_disconnected() {
+            return Err(pyo3::exceptions::PyRuntimeError::new_err(
+                "voice connection not ready",
+            ));
+        }
+
+        if let Some(player) = &self.player {
+            player.stop();
+        }
+
+        let loop_ = self.loop_.clone_ref(py);
+        let callbacks = Arc::clone(&self.callbacks);
+        let player = player::AudioPlayer::new(
+            move |index, reason, error| {
+                if let (None, Some(error)) = (&after, &error) {
+                    println!("Audio Player Error: {:?}", error);
+                }
+
+                let gil = Python::acquire_gil();
+                let py = gil.python();
+                let error = match error {
+                    Some(error) => PyErr::from(error).to_object(py),
+                    None => py.None(),
+                };
+                match track_finished_event(py, index, reason.as_str(), error.clone_ref(py)) {
+                    Ok(event) => callbacks.emit(py, &loop_, event),
+                    Err(e) => e.print(py),
+                }
+
+                if let Some(after) = &after {
+                    let args = (after.clone_ref(py), error, reason.as_str(), index);
+                    if let Err(e) = loop_.call_method1(py, "call_soon_threadsafe", args) {
+                        e.print(py);
+                    }
+                }
+            },
+            Arc::clone(&self.protocol),
+            Arc::new(Mutex::new(source)),
+            Arc::clone(&self.bitrate),
+            self.encoder_options,
+            payloads::SpeakingFlags::new(speaking),
+            send_policy,
+            Arc::clone(&self.packet_loss),
+            Arc::clone(&self.muted),
+            Arc::clone(&self.pcm_tap),
+        );
+
+        self.player = Some(player);
+        Ok(())
+    }
+}
+
 #[pyclass]
 struct VoiceConnector {
     #[pyo3(get, set)]
@@ -183,11 +1631,21 @@ struct VoiceConnector {
     #[pyo3(get, set)]
     user_id: u64,
     token: String,
+    reconnect_policy: protocol::ReconnectPolicy,
+    tls: protocol::TlsOptions,
+    preferred_encryption: Option<payloads::EncryptionMode>,
+    #[pyo3(get)]
+    max_message_size: usize,
+    preferred_ip_family: Option<protocol::IpFamily>,
+    connect_timeout: Duration,
+    read_timeout: Duration,
 }
 
 // __new__ -> VoiceConnector
 // update_socket -> bool
 // connect -> Future<()>
+// connect_and_run -> Future<VoiceConnection>
+// restore -> Future<VoiceConnection>
 // disconnect -> None
 
 #[pymethods]
@@ -200,6 +1658,13 @@ impl VoiceConnector {
             token: String::new(),
             server_id: String::new(),
             user_id: 0,
+            reconnect_policy: protocol::ReconnectPolicy::default(),
+            tls: protocol::TlsOptions::default(),
+            preferred_encryption: None,
+            max_message_size: protocol::DEFAULT_MAX_MESSAGE_SIZE,
+            preferred_ip_family: None,
+            connect_timeout: protocol::DEFAULT_CONNECT_TIMEOUT,
+            read_timeout: protocol::POLL_TIMEOUT,
         }
     }
 
@@ -215,6 +1680,130 @@ impl VoiceConnector {
         Ok(())
     }
 
+    /// Sets how resumable disconnects are retried before giving up with a ReconnectError.
+    /// Delays are in seconds.
+    #[text_signature = "(base_delay, max_delay, multiplier, max_attempts, /)"]
+    fn set_reconnect_policy(
+        &mut self,
+        base_delay: f64,
+        max_delay: f64,
+        multiplier: f64,
+        max_attempts: u32,
+    ) -> PyResult<()> {
+        self.reconnect_policy =
+            reconnect_policy_from_args(base_delay, max_delay, multiplier, max_attempts)?;
+        Ok(())
+    }
+
+    /// The encryption mode to use whenever the voice server offers it, rather than
+    /// the best one it offers. None (the default) always picks the best one.
+    #[getter]
+    fn preferred_encryption(&self) -> Option<String> {
+        self.preferred_encryption.map(Into::into)
+    }
+
+    #[setter]
+    fn set_preferred_encryption(&mut self, mode: Option<&str>) -> PyResult<()> {
+        self.preferred_encryption = match mode {
+            Some(mode) => match payloads::EncryptionMode::from_str(mode) {
+                Ok(mode) => Some(mode),
+                Err(_) => {
+                    return Err(pyo3::exceptions::PyValueError::new_err(
+                        "unknown encryption mode",
+                    ))
+                }
+            },
+            None => None,
+        };
+        Ok(())
+    }
+
+    /// The address family, "ipv4" or "ipv6", tried first when the voice endpoint
+    /// resolves to both. None (the default) tries them in the resolver's order.
+    /// Every address is tried either way, so a broken family only slows connecting.
+    #[getter]
+    fn preferred_ip_family(&self) -> Option<&'static str> {
+        self.preferred_ip_family.map(|family| match family {
+            protocol::IpFamily::V4 => "ipv4",
+            protocol::IpFamily::V6 => "ipv6",
+        })
+    }
+
+    #[setter]
+    fn set_preferred_ip_family(&mut self, family: Option<&str>) -> PyResult<()> {
+        self.preferred_ip_family = match family {
+            Some("ipv4") => Some(protocol::IpFamily::V4),
+            Some("ipv6") => Some(protocol::IpFamily::V6),
+            Some(_) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(
+                    "ip family must be 'ipv4', 'ipv6' or None",
+                ))
+            }
+            None => None,
+        };
+        Ok(())
+    }
+
+    /// Sets how long connecting to each of the voice endpoint's addresses may take,
+    /// with the TLS and websocket handshakes getting as long again, and how long each
+    /// read of the websocket waits for a message. Both are in seconds. The read
+    /// timeout is also how long queued payloads can wait, so it should stay short.
+    #[args(connect = "10.0", read = "0.1")]
+    #[text_signature = "(connect=10.0, read=0.1)"]
+    fn set_timeouts(&mut self, connect: f64, read: f64) -> PyResult<()> {
+        if !connect.is_finite() || connect <= 0.0 || !read.is_finite() || read <= 0.0 {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "timeouts must be a positive number of seconds",
+            ));
+        }
+        self.connect_timeout = Duration::from_secs_f64(connect);
+        self.read_timeout = Duration::from_secs_f64(read);
+        Ok(())
+    }
+
+    /// The largest websocket message accepted from the voice server, in bytes.
+    /// A larger one closes the connection instead of being parsed.
+    #[setter]
+    fn set_max_message_size(&mut self, size: usize) -> PyResult<()> {
+        if size == 0 {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "max_message_size must be positive",
+            ));
+        }
+        self.max_message_size = size;
+        Ok(())
+    }
+
+    /// Sets how the voice websocket's certificate is verified by connections made
+    /// after this. root_certificate is a PEM encoded CA certificate (as bytes) to
+    /// trust in addition to the system's, e.g. for a proxy with its own CA.
+    /// danger_accept_invalid_certs turns verification off entirely, which exposes
+    /// the token and the voice secret key to anyone in the middle. It's only meant
+    /// for testing against a mock voice server.
+    #[args(danger_accept_invalid_certs = "false", root_certificate = "None")]
+    #[text_signature = "(danger_accept_invalid_certs=False, root_certificate=None)"]
+    fn set_tls_options(
+        &mut self,
+        danger_accept_invalid_certs: bool,
+        root_certificate: Option<&PyBytes>,
+    ) -> PyResult<()> {
+        let root_certificate = root_certificate.map(|pem| pem.as_bytes().to_vec());
+        if let Some(ref pem) = root_certificate {
+            if let Err(e) = native_tls::Certificate::from_pem(pem) {
+                return Err(pyo3::exceptions::PyValueError::new_err(format!(
+                    "invalid root certificate: {}",
+                    e
+                )));
+            }
+        }
+
+        self.tls = protocol::TlsOptions {
+            danger_accept_invalid_certs,
+            root_certificate,
+        };
+        Ok(())
+    }
+
     #[text_signature = "(loop, /)"]
     fn connect(&mut self, py: Python, loop_: PyObject) -> PyResult<PyObject> {
         let (future, result): (PyObject, PyObject) = {
@@ -222,13 +1811,7 @@ impl VoiceConnector {
             (fut.clone_ref(py), fut)
         };
 
-        let mut builder = protocol::ProtocolBuilder::new(self.endpoint.clone());
-        builder
-            .server(self.server_id.clone())
-            .session(self.session_id.clone())
-            .auth(self.token.clone())
-            .user(self.user_id.to_string());
-
+        let builder = self.builder();
         thread::spawn(move || {
             let result = {
                 match builder.connect() {
@@ -241,30 +1824,209 @@ impl VoiceConnector {
             let _ = match result {
                 Err(e) => set_exception(py, loop_, future, PyErr::from(e)),
                 Ok(protocol) => {
-                    let object = VoiceConnection {
-                        protocol: Arc::new(Mutex::new(protocol)),
-                        player: None,
-                    };
+                    let object = VoiceConnection::new(loop_.clone_ref(py), protocol, None);
                     set_result(py, loop_, future, object.into_py(py))
                 }
             };
         });
         Ok(result)
     }
+
+    /// Connects and starts polling the connection in the same background thread,
+    /// so there's no separate call to VoiceConnection.run.
+    /// The returned future resolves to the running VoiceConnection once the
+    /// handshake is done. Errors that end the connection afterwards are set on
+    /// its closed future instead, as they would be on the future run returns.
+    #[text_signature = "(loop, /)"]
+    fn connect_and_run(&mut self, py: Python, loop_: PyObject) -> PyResult<PyObject> {
+        let (future, result): (PyObject, PyObject) = {
+            let fut: PyObject = loop_.call_method0(py, "create_future")?.into();
+            (fut.clone_ref(py), fut)
+        };
+        let closed: PyObject = loop_.call_method0(py, "create_future")?.into();
+
+        let builder = self.builder();
+        thread::spawn(move || {
+            let result = match builder.connect() {
+                Err(e) => Err(e),
+                Ok(mut protocol) => protocol.finish_flow(false).and(Ok(protocol)),
+            };
+            run_connection(result, loop_, future, closed);
+        });
+        Ok(result)
+    }
+
+    /// Picks a voice session back up from a dict get_state returned, e.g. after the
+    /// process restarts, by opening a new websocket and resuming the session rather
+    /// than going through a full voice connection again. The dict needs at least
+    /// secret_key, encryption_mode, endpoint, endpoint_ip, port, token, ssrc,
+    /// session_id, server_id and user_id, otherwise KeyError is raised.
+    /// If the new UDP socket is discovered at another address than the old one was,
+    /// that address is selected once the session is resumed. If the session can't be
+    /// resumed, a new session is identified instead.
+    /// The credentials in the dict replace the connector's, while its other settings
+    /// (reconnect policy, TLS, timeouts and so on) are used as they are.
+    /// The returned future resolves to the running VoiceConnection once that's done,
+    /// the same as connect_and_run.
+    #[text_signature = "(state, loop, /)"]
+    fn restore(&mut self, py: Python, state: &PyDict, loop_: PyObject) -> PyResult<PyObject> {
+        let secret_key: Vec<u8> = required_item(state, "secret_key")?;
+        if secret_key.len() != 32 {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "secret_key must be 32 bytes",
+            ));
+        }
+        let mode: String = required_item(state, "encryption_mode")?;
+        let encryption = payloads::EncryptionMode::from_str(mode.as_str())
+            .map_err(|_| pyo3::exceptions::PyValueError::new_err("unknown encryption mode"))?;
+        let discovered_ip: Option<String> = optional_item(state, "discovered_ip")?;
+        let discovered_port: Option<u16> = optional_item(state, "discovered_port")?;
+        let discovered = match (discovered_ip, discovered_port) {
+            (Some(ip), Some(port)) => Some((ip, port)),
+            _ => None,
+        };
+
+        let mut saved = protocol::SavedSession {
+            endpoint_ip: required_item(state, "endpoint_ip")?,
+            port: required_item(state, "port")?,
+            ssrc: required_item(state, "ssrc")?,
+            encryption,
+            secret_key: [0; 32],
+            discovered,
+        };
+        saved.secret_key.copy_from_slice(&secret_key);
+
+        // Everything is read first so a bad dict leaves the connector as it was
+        let endpoint = required_item(state, "endpoint")?;
+        let server_id = required_item(state, "server_id")?;
+        let session_id = required_item(state, "session_id")?;
+        let token = required_item(state, "token")?;
+        self.user_id = required_item(state, "user_id")?;
+        self.update_socket(token, server_id, endpoint)?;
+        self.session_id = session_id;
+
+        let (future, result): (PyObject, PyObject) = {
+            let fut: PyObject = loop_.call_method0(py, "create_future")?.into();
+            (fut.clone_ref(py), fut)
+        };
+        let closed: PyObject = loop_.call_method0(py, "create_future")?.into();
+
+        let builder = self.builder();
+        thread::spawn(move || {
+            let result = builder.connect().and_then(|mut protocol| {
+                protocol.restore_session(saved)?;
+                protocol.finish_flow(true)?;
+                Ok(protocol)
+            });
+            run_connection(result, loop_, future, closed);
+        });
+        Ok(result)
+    }
+}
+
+/// Resolves the future with a VoiceConnection for the protocol and then polls it
+/// on the current thread until it closes, setting the error it closed with on the
+/// closed future. If connecting failed the future gets the error instead.
+fn run_connection(
+    result: Result<protocol::DiscordVoiceProtocol, error::ProtocolError>,
+    loop_: PyObject,
+    future: PyObject,
+    closed: PyObject,
+) {
+    let (proto, callbacks) = {
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+        let protocol = match result {
+            Err(e) => {
+                let _ = set_exception(py, loop_, future, PyErr::from(e));
+                return;
+            }
+            Ok(protocol) => protocol,
+        };
+
+        let object =
+            VoiceConnection::new(loop_.clone_ref(py), protocol, Some(closed.clone_ref(py)));
+        let proto = Arc::clone(&object.protocol);
+        let callbacks = Arc::clone(&object.callbacks);
+        let _ = set_result(py, loop_.clone_ref(py), future, object.into_py(py));
+        (proto, callbacks)
+    };
+
+    let e = poll_until_closed(&proto, &loop_, &callbacks);
+    let gil = Python::acquire_gil();
+    let py = gil.python();
+    let _ = set_exception(py, loop_, closed, PyErr::from(e));
+}
+
+/// Extracts a key from a state dict, raising KeyError if it's missing
+fn required_item<'a, T: FromPyObject<'a>>(state: &'a PyDict, key: &str) -> PyResult<T> {
+    match state.get_item(key) {
+        Some(value) => value.extract(),
+        None => Err(pyo3::exceptions::PyKeyError::new_err(format!(
+            "state is missing {:?}",
+            key
+        ))),
+    }
+}
+
+/// Extracts a key from a state dict, with None if it's missing
+fn optional_item<'a, T: FromPyObject<'a>>(state: &'a PyDict, key: &str) -> PyResult<Option<T>> {
+    match state.get_item(key) {
+        Some(value) => value.extract(),
+        None => Ok(None),
+    }
 }
 
-use xsalsa20poly1305::aead::{generic_array::GenericArray, Aead, AeadInPlace, Buffer, NewAead};
-use xsalsa20poly1305::XSalsa20Poly1305;
+impl VoiceConnector {
+    fn builder(&self) -> protocol::ProtocolBuilder {
+        let mut builder = protocol::ProtocolBuilder::new(self.endpoint.clone());
+        builder
+            .server(self.server_id.clone())
+            .session(self.session_id.clone())
+            .auth(self.token.clone())
+            .user(self.user_id.to_string())
+            .reconnect_policy(self.reconnect_policy)
+            .tls(self.tls.clone())
+            .preferred_encryption(self.preferred_encryption)
+            .max_message_size(self.max_message_size)
+            .preferred_family(self.preferred_ip_family)
+            .connect_timeout(self.connect_timeout)
+            .read_timeout(self.read_timeout);
+        builder
+    }
+}
+
+use std::str::FromStr;
+use xsalsa20poly1305::aead::Buffer;
 
 #[pyclass]
 struct Debugger {
     opus: audiopus::coder::Encoder,
-    cipher: XSalsa20Poly1305,
+    encryption: payloads::EncryptionMode,
+    cipher: player::VoiceCipher,
+    encrypter: player::Encrypter,
     sequence: u16,
     timestamp: u32,
     #[pyo3(get, set)]
     ssrc: u32,
     lite_nonce: u32,
+    /// The RTP version, the top two bits of the first byte
+    version: u8,
+    /// The RTP marker bit, the top bit of the second byte
+    #[pyo3(get, set)]
+    marker: bool,
+    /// The RTP payload type, the rest of the second byte
+    payload_type: u8,
+}
+
+impl Debugger {
+    /// The first two bytes of the RTP header, 0x80 0x78 for Discord
+    fn header_bytes(&self) -> [u8; 2] {
+        [
+            self.version << 6,
+            (self.marker as u8) << 7 | self.payload_type,
+        ]
+    }
 }
 
 fn get_encoder() -> Result<audiopus::coder::Encoder, error::ProtocolError> {
@@ -275,30 +2037,95 @@ fn get_encoder() -> Result<audiopus::coder::Encoder, error::ProtocolError> {
     )?;
 
     encoder.set_bitrate(audiopus::Bitrate::BitsPerSecond(128 * 1024))?;
-    encoder.enable_inband_fec()?;
-    encoder.set_packet_loss_perc(15)?;
-    encoder.set_bandwidth(audiopus::Bandwidth::Fullband)?;
-    encoder.set_signal(audiopus::Signal::Auto)?;
+    player::EncoderOptions::default().apply(&mut encoder)?;
     Ok(encoder)
 }
 
+/// Creates a cipher from a user supplied key, which would otherwise panic if it's the wrong size
+fn cipher_from_key(
+    mode: payloads::EncryptionMode,
+    secret_key: &[u8],
+) -> PyResult<player::VoiceCipher> {
+    if secret_key.len() != 32 {
+        return Err(pyo3::exceptions::PyValueError::new_err(
+            "secret key must be 32 bytes",
+        ));
+    }
+
+    let mut key = [0u8; 32];
+    key.copy_from_slice(secret_key);
+    Ok(player::VoiceCipher::new(mode, &key))
+}
+
+fn nonce_from_bytes(nonce: &[u8]) -> PyResult<[u8; 24]> {
+    if nonce.len() != 24 {
+        return Err(pyo3::exceptions::PyValueError::new_err(
+            "nonce must be 24 bytes",
+        ));
+    }
+
+    let mut result = [0u8; 24];
+    result.copy_from_slice(nonce);
+    Ok(result)
+}
+
 #[pymethods]
 impl Debugger {
+    /// Packets are framed the same way as the player does for the given encryption mode
     #[new]
-    fn new(secret_key: Vec<u8>) -> PyResult<Self> {
+    #[args(mode = "\"xsalsa20_poly1305_lite\"")]
+    fn new(secret_key: Vec<u8>, mode: &str) -> PyResult<Self> {
+        let encryption = payloads::EncryptionMode::from_str(mode)
+            .map_err(|_| pyo3::exceptions::PyValueError::new_err("unknown encryption mode"))?;
         let encoder = get_encoder()?;
-        let key = GenericArray::clone_from_slice(secret_key.as_ref());
-        let cipher = XSalsa20Poly1305::new(&key);
+        let cipher = cipher_from_key(encryption, secret_key.as_ref())?;
         Ok(Self {
             opus: encoder,
+            encryption,
             cipher,
+            encrypter: player::encrypter_for(encryption),
             sequence: 0,
             timestamp: 0,
             ssrc: 0,
             lite_nonce: 0,
+            version: 2,
+            marker: false,
+            payload_type: 0x78,
         })
     }
 
+    #[getter]
+    fn version(&self) -> u8 {
+        self.version
+    }
+
+    #[setter]
+    fn set_version(&mut self, version: u8) -> PyResult<()> {
+        if version > 3 {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "version must fit in 2 bits",
+            ));
+        }
+        self.version = version;
+        Ok(())
+    }
+
+    #[getter]
+    fn payload_type(&self) -> u8 {
+        self.payload_type
+    }
+
+    #[setter]
+    fn set_payload_type(&mut self, payload_type: u8) -> PyResult<()> {
+        if payload_type > 0x7F {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "payload type must fit in 7 bits",
+            ));
+        }
+        self.payload_type = payload_type;
+        Ok(())
+    }
+
     fn encode_opus<'py>(&self, py: Python<'py>, buffer: &PyBytes) -> PyResult<&'py PyBytes> {
         let bytes = buffer.as_bytes();
         if bytes.len() != 3840 {
@@ -307,8 +2134,14 @@ impl Debugger {
             ));
         }
 
-        let as_i16: &[i16] =
-            unsafe { std::slice::from_raw_parts(bytes.as_ptr() as *const i16, bytes.len() / 2) };
+        let as_i16 = match player::pcm_from_le_bytes(bytes) {
+            Some(samples) => samples,
+            None => {
+                return Err(pyo3::exceptions::PyValueError::new_err(
+                    "byte length must be a multiple of 2",
+                ))
+            }
+        };
 
         let mut output = [0u8; 2000];
         match self.opus.encode(&as_i16, &mut output) {
@@ -323,15 +2156,77 @@ impl Debugger {
         nonce: &PyBytes,
         buffer: &PyBytes,
     ) -> PyResult<&'py PyBytes> {
-        let nonce = GenericArray::from_slice(nonce.as_bytes());
-        match self.cipher.encrypt(nonce, buffer.as_bytes()) {
-            Ok(text) => Ok(PyBytes::new(py, text.as_slice())),
+        let nonce = nonce_from_bytes(nonce.as_bytes())?;
+        let mut text = buffer.as_bytes().to_vec();
+        match self.cipher.encrypt_in_place(&nonce, b"", &mut text) {
+            Ok(_) => Ok(PyBytes::new(py, text.as_slice())),
             Err(_) => Err(pyo3::exceptions::PyRuntimeError::new_err(
                 "Could not encrypt for whatever reason",
             )),
         }
     }
 
+    fn decrypt<'py>(
+        &self,
+        py: Python<'py>,
+        nonce: &PyBytes,
+        buffer: &PyBytes,
+    ) -> PyResult<&'py PyBytes> {
+        let nonce = nonce_from_bytes(nonce.as_bytes())?;
+        let mut text = buffer.as_bytes().to_vec();
+        match self.cipher.decrypt_in_place(&nonce, b"", &mut text) {
+            Ok(_) => Ok(PyBytes::new(py, text.as_slice())),
+            Err(_) => Err(pyo3::exceptions::PyValueError::new_err(
+                "Could not decrypt, the nonce, key or data is wrong",
+            )),
+        }
+    }
+
+    /// Reverses prepare_packet, returning a dict with the marker, sequence, timestamp
+    /// and ssrc from the RTP header along with the decrypted Opus payload.
+    fn parse_packet<'py>(&self, py: Python<'py>, packet: &PyBytes) -> PyResult<&'py PyDict> {
+        let packet = packet.as_bytes();
+        if packet.len() <= receiver::RTP_HEADER_SIZE {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "packet is too short",
+            ));
+        }
+
+        // Only the version and payload type are checked, the other bits vary per packet
+        if packet[0] >> 6 != self.version || packet[1] & 0x7F != self.payload_type {
+            return Err(pyo3::exceptions::PyValueError::new_err(
+                "packet does not have the expected RTP version and payload type",
+            ));
+        }
+
+        // The nonce is reconstructed the same way the receiver does for this mode
+        let decrypter = receiver::decrypter_for(self.encryption);
+        let mut buffer = [0u8; player::MAX_BUFFER_SIZE];
+        let payload = match decrypter(&self.cipher, packet, &mut buffer) {
+            Ok(size) => &buffer[..size],
+            Err(_) => {
+                return Err(pyo3::exceptions::PyValueError::new_err(
+                    "Could not decrypt the packet",
+                ))
+            }
+        };
+
+        let header = &packet[..receiver::RTP_HEADER_SIZE];
+        let result = PyDict::new(py);
+        result.set_item("marker", header[1] & 0x80 != 0)?;
+        result.set_item("sequence", u16::from_be_bytes([header[2], header[3]]))?;
+        result.set_item(
+            "timestamp",
+            u32::from_be_bytes([header[4], header[5], header[6], header[7]]),
+        )?;
+        result.set_item(
+            "ssrc",
+            u32::from_be_bytes([header[8], header[9], header[10], header[11]]),
+        )?;
+        result.set_item("payload", PyBytes::new(py, payload))?;
+        Ok(result)
+    }
+
     fn prepare_packet<'py>(&mut self, py: Python<'py>, buffer: &PyBytes) -> PyResult<&'py PyBytes> {
         let bytes = buffer.as_bytes();
         if bytes.len() != 3840 {
@@ -340,50 +2235,70 @@ impl Debugger {
             ));
         }
 
-        let pcm: &[i16] =
-            unsafe { std::slice::from_raw_parts(bytes.as_ptr() as *const i16, bytes.len() / 2) };
+        let pcm = match player::pcm_from_le_bytes(bytes) {
+            Some(samples) => samples,
+            None => {
+                return Err(pyo3::exceptions::PyValueError::new_err(
+                    "byte length must be a multiple of 2",
+                ))
+            }
+        };
 
+        // The Opus data goes after the RTP header, which is written afterwards
         let mut output = [0u8; player::MAX_BUFFER_SIZE];
-        let offset = match self.opus.encode(&pcm, &mut output[12..]) {
+        let offset = match self.opus.encode(&pcm, &mut output[player::BUFFER_OFFSET..]) {
             Ok(size) => size,
             Err(e) => return Err(pyo3::exceptions::PyRuntimeError::new_err(e.to_string())),
         };
 
         self.sequence = self.sequence.wrapping_add(1);
-        output[0] = 0x80;
-        output[1] = 0x78;
+        output[0..2].copy_from_slice(&self.header_bytes());
         output[2..4].copy_from_slice(&self.sequence.to_be_bytes());
         output[4..8].copy_from_slice(&self.timestamp.to_be_bytes());
-        output[8..12].copy_from_slice(&self.ssrc.to_be_bytes());
-
-        let mut nonce = [0u8; 24];
-        nonce[0..4].copy_from_slice(&self.lite_nonce.to_be_bytes());
-        let mut buffer = player::InPlaceBuffer::new(&mut output[12..], offset);
-        if let Err(e) =
-            self.cipher
-                .encrypt_in_place(GenericArray::from_slice(&nonce), b"", &mut buffer)
-        {
-            return Err(pyo3::exceptions::PyRuntimeError::new_err(e.to_string()));
-        }
+        output[8..player::BUFFER_OFFSET].copy_from_slice(&self.ssrc.to_be_bytes());
 
-        if let Err(e) = buffer.extend_from_slice(&nonce) {
+        let (header, payload) = output.split_at_mut(player::BUFFER_OFFSET);
+        let mut buffer = player::InPlaceBuffer::new(payload, offset);
+        if let Err(e) = (self.encrypter)(&self.cipher, self.lite_nonce, header, &mut buffer) {
             return Err(pyo3::exceptions::PyRuntimeError::new_err(e.to_string()));
         }
 
         self.lite_nonce = self.lite_nonce.wrapping_add(1);
         self.timestamp = self.timestamp.wrapping_add(player::SAMPLES_PER_FRAME);
-        let size = buffer.len();
+        // The buffer only covers what comes after the header
+        let size = player::BUFFER_OFFSET + buffer.len();
         Ok(PyBytes::new(py, &output[0..size]))
     }
 }
 
+/// The lowest and highest bitrate (in bits per second) set_bitrate accepts, inclusive
+#[pyfunction]
+#[text_signature = "()"]
+fn bitrate_range() -> (i32, i32) {
+    (player::MIN_BITRATE, player::MAX_BITRATE)
+}
+
+/// The names of the bandwidths set_encoder_options accepts, "auto" followed by the
+/// rest from narrowest to widest
+#[pyfunction]
+#[text_signature = "()"]
+fn bandwidths() -> Vec<&'static str> {
+    player::BANDWIDTHS
+        .iter()
+        .map(|&bandwidth| player::bandwidth_name(bandwidth))
+        .collect()
+}
+
 #[pymodule]
 fn _native_voice(py: Python, m: &PyModule) -> PyResult<()> {
     m.add_class::<VoiceConnection>()?;
     m.add_class::<VoiceConnector>()?;
     m.add_class::<Debugger>()?;
+    m.add_wrapped(wrap_pyfunction!(bitrate_range))?;
+    m.add_wrapped(wrap_pyfunction!(bandwidths))?;
     m.add("ReconnectError", py.get_type::<ReconnectError>())?;
     m.add("ConnectionError", py.get_type::<ConnectionError>())?;
     m.add("ConnectionClosed", py.get_type::<ConnectionClosed>())?;
+    m.add("FFmpegNotFound", py.get_type::<FFmpegNotFound>())?;
     Ok(())
 }
@@ -1,7 +1,7 @@
 use serde::{Serialize, Deserialize};
 use serde_json::value::RawValue;
 
-use std::{str::FromStr, time::{SystemTime, UNIX_EPOCH, Instant}};
+use std::str::FromStr;
 use crate::error::{custom_error, ProtocolError};
 
 // Static typed models to convert to
@@ -123,18 +123,11 @@ pub struct Heartbeat {
 }
 
 impl Heartbeat {
-    pub fn new(instant: Instant) -> Self {
+    /// The nonce is echoed back by Discord in the HEARTBEAT_ACK payload
+    pub fn new(nonce: u64) -> Self {
         Self {
             op: Opcode::HEARTBEAT,
-            d: instant.elapsed().as_millis() as u64,
-        }
-    }
-
-    pub fn now() -> Self {
-        let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("time went backwards");
-        Self {
-            op: Opcode::HEARTBEAT,
-            d: now.as_millis() as u64,
+            d: nonce,
         }
     }
 }
@@ -196,7 +189,7 @@ impl SpeakingFlags {
 #[derive(Debug, Clone, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub struct SpeakingInfo {
     speaking: u8,
-    delay: u8,
+    delay: u32,
 }
 
 #[derive(Debug, Clone, Eq, Hash, PartialEq, Serialize, Deserialize)]
@@ -206,11 +199,13 @@ pub struct Speaking {
 }
 
 impl Speaking {
-    pub fn new(flags: SpeakingFlags) -> Self {
+    /// The delay (in milliseconds) should be 0 for bots. It is only meant for
+    /// relaying continuous audio that is buffered before it reaches Discord.
+    pub fn new(flags: SpeakingFlags, delay: u32) -> Self {
         Self {
             op: Opcode::SPEAKING,
             d: SpeakingInfo {
-                delay: 0,
+                delay,
                 speaking: flags.value,
             }
         }
@@ -219,6 +214,13 @@ impl Speaking {
 
 // These are receive only
 
+#[derive(Debug, Clone, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub struct SpeakingUpdate {
+    pub user_id: String,
+    pub ssrc: u32,
+    pub speaking: u8,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RawReceivedPayload<'a> {
     pub op: u8,
@@ -231,7 +233,7 @@ pub struct RawReceivedPayload<'a> {
 pub struct Resumed;
 
 #[derive(Debug, Clone, Eq, Hash, PartialEq, Serialize, Deserialize)]
-pub struct HeartbeatAck(u64);
+pub struct HeartbeatAck(pub u64);
 
 #[derive(Debug, Clone, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub struct SessionDescription {
@@ -239,14 +241,28 @@ pub struct SessionDescription {
     pub secret_key: [u8; 32],
 }
 
+/// The heartbeat interval comes from HELLO. READY used to carry a heartbeat_interval
+/// as well but it was never the one to use, so it's ignored along with any other
+/// fields that aren't listed here.
 #[derive(Debug, Clone, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub struct Ready {
     pub ssrc: u32,
     pub ip: String,
     pub port: u16,
     pub modes: Vec<String>,
-    #[serde(skip)]
-    heartbeat_interval: u16,
+}
+
+#[derive(Debug, Clone, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub struct ClientConnect {
+    pub user_id: String,
+    pub audio_ssrc: u32,
+    #[serde(default)]
+    pub video_ssrc: u32,
+}
+
+#[derive(Debug, Clone, Eq, Hash, PartialEq, Serialize, Deserialize)]
+pub struct ClientDisconnect {
+    pub user_id: String,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -255,11 +271,12 @@ pub struct Hello {
 }
 
 /// These are encryption modes ordered by priority
-#[derive(PartialOrd, Ord, Eq, PartialEq, Copy, Clone)]
+#[derive(Debug, PartialOrd, Ord, Eq, PartialEq, Copy, Clone)]
 pub enum EncryptionMode {
     XSalsa20Poly1305 = 0,
     XSalsa20Poly1305Suffix = 1,
     XSalsa20Poly1305Lite = 2,
+    AeadXChaCha20Poly1305RtpSize = 3,
 }
 
 impl Default for EncryptionMode {
@@ -274,10 +291,21 @@ impl Into<String> for EncryptionMode {
             EncryptionMode::XSalsa20Poly1305 => "xsalsa20_poly1305".to_owned(),
             EncryptionMode::XSalsa20Poly1305Suffix => "xsalsa20_poly1305_suffix".to_owned(),
             EncryptionMode::XSalsa20Poly1305Lite => "xsalsa20_poly1305_lite".to_owned(),
+            EncryptionMode::AeadXChaCha20Poly1305RtpSize => {
+                "aead_xchacha20_poly1305_rtpsize".to_owned()
+            }
         }
     }
 }
 
+impl EncryptionMode {
+    /// Whether only the RTP payload is encrypted, with the header
+    /// (and extension header) sent in the clear as associated data.
+    pub fn is_rtpsize(&self) -> bool {
+        *self == EncryptionMode::AeadXChaCha20Poly1305RtpSize
+    }
+}
+
 impl FromStr for EncryptionMode {
     type Err = ProtocolError;
 
@@ -286,6 +314,7 @@ impl FromStr for EncryptionMode {
             "xsalsa20_poly1305_lite" => Ok(EncryptionMode::XSalsa20Poly1305Lite),
             "xsalsa20_poly1305_suffix" => Ok(EncryptionMode::XSalsa20Poly1305Suffix),
             "xsalsa20_poly1305" => Ok(EncryptionMode::XSalsa20Poly1305),
+            "aead_xchacha20_poly1305_rtpsize" => Ok(EncryptionMode::AeadXChaCha20Poly1305RtpSize),
             _ => Err(custom_error("unknown encryption mode"))
         }
     }
@@ -299,4 +328,55 @@ impl Ready {
                   .max()
                   .ok_or(custom_error("No best supported encryption mode found"))
     }
+
+    /// Picks the preferred mode if the server offers it, otherwise the best supported one
+    pub fn select_encryption_mode(
+        &self,
+        preferred: Option<EncryptionMode>,
+    ) -> Result<EncryptionMode, ProtocolError> {
+        if let Some(mode) = preferred {
+            let name: String = mode.into();
+            if self.modes.contains(&name) {
+                return Ok(mode);
+            }
+        }
+        self.get_encryption_mode()
+    }
+}
+
+pub(crate) fn parse_snowflake(value: &str) -> Result<u64, ProtocolError> {
+    value
+        .parse::<u64>()
+        .map_err(|_| custom_error("invalid snowflake ID"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ready_from_gateway() {
+        // As sent by the voice gateway, including the heartbeat_interval that is ignored
+        let text = r#"{"op":2,"d":{"ssrc":24871,"ip":"198.51.100.7","port":50004,"modes":["aead_xchacha20_poly1305_rtpsize","xsalsa20_poly1305_lite","xsalsa20_poly1305_suffix","xsalsa20_poly1305"],"experiments":["bwe_conservative_link_estimate"],"heartbeat_interval":1}}"#;
+        let payload: RawReceivedPayload = serde_json::from_str(text).unwrap();
+        assert_eq!(payload.op, Opcode::READY);
+
+        let ready: Ready = serde_json::from_str(payload.d.get()).unwrap();
+        assert_eq!(ready.ssrc, 24871);
+        assert_eq!(ready.ip, "198.51.100.7");
+        assert_eq!(ready.port, 50004);
+        assert_eq!(
+            ready.modes,
+            vec![
+                "aead_xchacha20_poly1305_rtpsize",
+                "xsalsa20_poly1305_lite",
+                "xsalsa20_poly1305_suffix",
+                "xsalsa20_poly1305",
+            ]
+        );
+        assert_eq!(
+            ready.get_encryption_mode().unwrap(),
+            EncryptionMode::AeadXChaCha20Poly1305RtpSize
+        );
+    }
 }
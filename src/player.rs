@@ -1,19 +1,27 @@
-use crate::error::ProtocolError;
+use crate::error::{custom_error, ProtocolError};
 use crate::payloads::{EncryptionMode, SpeakingFlags};
 use crate::protocol::DiscordVoiceProtocol;
 use crate::state::PlayingState;
 
 use parking_lot::Mutex;
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
 use std::io::ErrorKind;
-use std::io::Read;
+use std::io::{BufRead, BufReader, Read};
 use std::net::UdpSocket;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::thread;
 use std::time::{Duration, Instant};
 
 use std::process::{Child, Command, Stdio};
 
-use rand::RngCore;
+use chacha20poly1305::XChaCha20Poly1305;
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender, TrySendError};
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
 use xsalsa20poly1305::aead::Buffer;
 use xsalsa20poly1305::aead::{generic_array::GenericArray, AeadInPlace, NewAead};
 use xsalsa20poly1305::XSalsa20Poly1305;
@@ -24,12 +32,301 @@ pub const FRAME_LENGTH: u16 = 20;
 pub const SAMPLE_SIZE: u16 = 4; // 16-bits / 8 * channels
 pub const SAMPLES_PER_FRAME: u32 = ((SAMPLING_RATE / 1000) * FRAME_LENGTH) as u32;
 pub const FRAME_SIZE: u32 = SAMPLES_PER_FRAME * SAMPLE_SIZE as u32;
+/// The frame lengths (in milliseconds) Opus can encode, FRAME_LENGTH being the default
+pub const FRAME_LENGTHS: [u16; 4] = [10, 20, 40, 60];
+/// The number of samples in the longest PCM frame, 60ms of stereo
+pub const MAX_FRAME_SAMPLES: usize = (SAMPLING_RATE / 1000) as usize * 60 * CHANNELS as usize;
+
+pub const DEFAULT_BITRATE: i32 = 128000;
+pub const MIN_BITRATE: i32 = 8000;
+pub const MAX_BITRATE: i32 = 512000;
+
+/// How long to wait for FFmpeg to produce a frame before giving up on it
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long SendPolicy::Block sleeps between retries
+const SEND_RETRY_INTERVAL: Duration = Duration::from_millis(1);
+/// The number of frames read ahead from FFmpeg, one second of audio
+const READ_AHEAD_FRAMES: usize = 50;
+/// The number of frames FFmpeg has to have read ahead before the first one is played
+pub const DEFAULT_PREBUFFER_FRAMES: usize = 5;
+/// How often the read ahead is checked while prebuffering
+const PREBUFFER_POLL_INTERVAL: Duration = Duration::from_millis(5);
+/// How many frames of FFmpeg's output are read from the pipe at once
+pub const READ_BUFFER_FRAMES: usize = 16;
+/// The number of lines of FFmpeg's stderr kept for error reporting
+const STDERR_TAIL_LINES: usize = 5;
+
+/// Tuning options for the Opus encoder.
+/// These are applied when the encoder is created at the start of playback.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EncoderOptions {
+    /// Whether inband forward error correction is enabled
+    pub fec: bool,
+    /// The expected packet loss percentage, between 0 and 100.
+    /// Raising this makes the encoder add more redundancy for lossy networks.
+    pub packet_loss_perc: u8,
+    pub signal: audiopus::Signal,
+    pub bandwidth: audiopus::Bandwidth,
+    /// Mono halves the PCM frame size, sources are then expected to produce mono frames
+    pub channels: audiopus::Channels,
+    /// If set, PCM frames where no sample is louder than this aren't encoded.
+    /// A few Opus silence frames are sent instead and the rest are suppressed.
+    pub silence_threshold: Option<u16>,
+    /// The length of each frame in milliseconds, one of FRAME_LENGTHS.
+    /// Longer frames have less overhead for music, shorter ones less latency for voice.
+    pub frame_length: u16,
+    pub bitrate_mode: BitrateMode,
+}
+
+/// How closely the encoder sticks to the bitrate it's given
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitrateMode {
+    /// The size of each frame follows how hard the audio is to encode. This sounds
+    /// the best for the bitrate on average but short bursts can go well over it.
+    Vbr,
+    /// Variable, but never more than the bitrate over a short window. This is the
+    /// Opus default and a good fit for most connections.
+    ConstrainedVbr,
+    /// Every frame is the same size, which wastes bits on easy audio but keeps the
+    /// bandwidth used exactly predictable, e.g. for a strict bandwidth budget.
+    Cbr,
+}
+
+impl Default for EncoderOptions {
+    fn default() -> Self {
+        Self {
+            fec: true,
+            packet_loss_perc: 15,
+            signal: audiopus::Signal::Auto,
+            bandwidth: audiopus::Bandwidth::Fullband,
+            channels: audiopus::Channels::Stereo,
+            silence_threshold: None,
+            frame_length: FRAME_LENGTH,
+            bitrate_mode: BitrateMode::ConstrainedVbr,
+        }
+    }
+}
+
+impl EncoderOptions {
+    pub fn channel_count(&self) -> u16 {
+        match self.channels {
+            audiopus::Channels::Mono => 1,
+            _ => CHANNELS,
+        }
+    }
+
+    /// The number of samples per channel in a single frame, which is also
+    /// how far the RTP timestamp moves on with every frame
+    pub fn samples_per_frame(&self) -> u32 {
+        (SAMPLING_RATE / 1000) as u32 * self.frame_length as u32
+    }
+
+    /// The number of samples in a single PCM frame
+    pub fn frame_samples(&self) -> usize {
+        self.samples_per_frame() as usize * self.channel_count() as usize
+    }
+
+    pub fn frame_duration(&self) -> Duration {
+        Duration::from_millis(self.frame_length as u64)
+    }
+
+    /// The settings an encoder created with these options and bitrate should have
+    pub fn info(&self, bitrate: i32) -> EncoderInfo {
+        EncoderInfo {
+            bitrate,
+            fec: self.fec,
+            packet_loss_perc: self.packet_loss_perc,
+            signal: self.signal,
+            bandwidth: self.bandwidth,
+            bitrate_mode: self.bitrate_mode,
+        }
+    }
+
+    pub fn apply(
+        &self,
+        encoder: &mut audiopus::coder::Encoder,
+    ) -> Result<(), audiopus::error::Error> {
+        if self.fec {
+            encoder.enable_inband_fec()?;
+        } else {
+            encoder.disable_inband_fec()?;
+        }
+        encoder.set_packet_loss_perc(self.packet_loss_perc)?;
+        encoder.set_bandwidth(self.bandwidth)?;
+        encoder.set_signal(self.signal)?;
+        match self.bitrate_mode {
+            BitrateMode::Vbr => {
+                encoder.set_vbr(true)?;
+                encoder.set_vbr_constraint(false)?;
+            }
+            BitrateMode::ConstrainedVbr => {
+                encoder.set_vbr(true)?;
+                encoder.set_vbr_constraint(true)?;
+            }
+            BitrateMode::Cbr => encoder.set_vbr(false)?,
+        }
+        Ok(())
+    }
+}
+
+/// What to do when a packet can't be sent right away because the socket's send buffer is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendPolicy {
+    /// Drop the packet, which is best for live audio
+    Drop,
+    /// Keep retrying for up to a frame's duration before dropping it,
+    /// which is better for pre-rendered audio where every frame matters
+    Block,
+}
+
+pub fn parse_send_policy(value: &str) -> Option<SendPolicy> {
+    match value {
+        "drop" => Some(SendPolicy::Drop),
+        "block" => Some(SendPolicy::Block),
+        _ => None,
+    }
+}
+
+pub fn parse_signal(value: &str) -> Option<audiopus::Signal> {
+    match value {
+        "auto" => Some(audiopus::Signal::Auto),
+        "voice" => Some(audiopus::Signal::Voice),
+        "music" => Some(audiopus::Signal::Music),
+        _ => None,
+    }
+}
+
+pub fn parse_bandwidth(value: &str) -> Option<audiopus::Bandwidth> {
+    match value {
+        "auto" => Some(audiopus::Bandwidth::Auto),
+        "narrow" => Some(audiopus::Bandwidth::Narrowband),
+        "medium" => Some(audiopus::Bandwidth::Mediumband),
+        "wide" => Some(audiopus::Bandwidth::Wideband),
+        "superwide" => Some(audiopus::Bandwidth::Superwideband),
+        "full" => Some(audiopus::Bandwidth::Fullband),
+        _ => None,
+    }
+}
+
+pub fn parse_bitrate_mode(value: &str) -> Option<BitrateMode> {
+    match value {
+        "vbr" => Some(BitrateMode::Vbr),
+        "constrained" => Some(BitrateMode::ConstrainedVbr),
+        "cbr" => Some(BitrateMode::Cbr),
+        _ => None,
+    }
+}
+
+/// The name parse_signal accepts for the signal type
+pub fn signal_name(signal: audiopus::Signal) -> &'static str {
+    match signal {
+        audiopus::Signal::Auto => "auto",
+        audiopus::Signal::Voice => "voice",
+        audiopus::Signal::Music => "music",
+    }
+}
+
+/// Every bandwidth the encoder can be set to, from narrowest to widest
+pub const BANDWIDTHS: [audiopus::Bandwidth; 6] = [
+    audiopus::Bandwidth::Auto,
+    audiopus::Bandwidth::Narrowband,
+    audiopus::Bandwidth::Mediumband,
+    audiopus::Bandwidth::Wideband,
+    audiopus::Bandwidth::Superwideband,
+    audiopus::Bandwidth::Fullband,
+];
+
+/// The name parse_bandwidth accepts for the bandwidth
+pub fn bandwidth_name(bandwidth: audiopus::Bandwidth) -> &'static str {
+    match bandwidth {
+        audiopus::Bandwidth::Auto => "auto",
+        audiopus::Bandwidth::Narrowband => "narrow",
+        audiopus::Bandwidth::Mediumband => "medium",
+        audiopus::Bandwidth::Wideband => "wide",
+        audiopus::Bandwidth::Superwideband => "superwide",
+        audiopus::Bandwidth::Fullband => "full",
+    }
+}
+
+/// The name parse_bitrate_mode accepts for the bitrate mode
+pub fn bitrate_mode_name(mode: BitrateMode) -> &'static str {
+    match mode {
+        BitrateMode::Vbr => "vbr",
+        BitrateMode::ConstrainedVbr => "constrained",
+        BitrateMode::Cbr => "cbr",
+    }
+}
+
+/// The settings in effect on an Opus encoder
+#[derive(Debug, Clone, Copy)]
+pub struct EncoderInfo {
+    pub bitrate: i32,
+    pub fec: bool,
+    pub packet_loss_perc: u8,
+    pub signal: audiopus::Signal,
+    pub bandwidth: audiopus::Bandwidth,
+    pub bitrate_mode: BitrateMode,
+}
+
+/// Interprets 16-bit little-endian PCM bytes as samples.
+/// The bytes are borrowed as-is when they're suitably aligned on a little-endian host,
+/// otherwise the samples are decoded one by one.
+/// Returns None if the length isn't a multiple of 2.
+pub fn pcm_from_le_bytes(bytes: &[u8]) -> Option<Cow<'_, [i16]>> {
+    if bytes.len() % 2 != 0 {
+        return None;
+    }
+
+    if cfg!(target_endian = "little") {
+        // This is fine since every bit pattern is a valid i16
+        let (prefix, samples, suffix) = unsafe { bytes.align_to::<i16>() };
+        if prefix.is_empty() && suffix.is_empty() {
+            return Some(Cow::Borrowed(samples));
+        }
+    }
+
+    let samples = bytes
+        .chunks_exact(2)
+        .map(|pair| i16::from_le_bytes([pair[0], pair[1]]))
+        .collect();
+    Some(Cow::Owned(samples))
+}
+
+/// Decodes 16-bit little-endian PCM into the buffer regardless of the host's endianness.
+/// If there are fewer bytes than needed then the rest of the buffer is zeroed.
+pub fn decode_pcm_le(bytes: &[u8], buffer: &mut [i16]) {
+    let mut pairs = bytes.chunks_exact(2);
+    for sample in buffer.iter_mut() {
+        *sample = match pairs.next() {
+            Some(pair) => i16::from_le_bytes([pair[0], pair[1]]),
+            None => 0,
+        };
+    }
+}
+
+/// Encodes PCM as 16-bit little-endian bytes, the reverse of decode_pcm_le
+pub fn encode_pcm_le(samples: &[i16]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(samples.len() * 2);
+    for sample in samples {
+        bytes.extend_from_slice(&sample.to_le_bytes());
+    }
+    bytes
+}
 
 pub enum AudioType {
     Opus,
     Pcm,
 }
 
+impl AudioType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AudioType::Opus => "opus",
+            AudioType::Pcm => "pcm",
+        }
+    }
+}
+
 pub trait AudioSource: Send {
     /// The audio type of this source
     /// If AudioType is Opus then the data will be passed as-is to discord
@@ -38,6 +335,8 @@ pub trait AudioSource: Send {
     }
 
     /// Reads a frame of audio (20ms 16-bit stereo 48000Hz)
+    /// If the encoder is configured for mono then the buffer is half the size
+    /// and should be filled with mono samples instead.
     /// Returns Some(num) where num is number of frames written to the buffer
     /// Returns None if the audio source has terminated
     /// This is only called if the AudioType is PCM.
@@ -49,41 +348,440 @@ pub trait AudioSource: Send {
     fn read_opus_frame(&mut self, _buffer: &mut [u8]) -> Option<usize> {
         unimplemented!()
     }
+
+    /// A short name for the kind of source, e.g. "ffmpeg"
+    fn kind(&self) -> &'static str {
+        "custom"
+    }
+
+    /// Whether this source supports seeking
+    fn is_seekable(&self) -> bool {
+        false
+    }
+
+    /// Seeks to the given position (in seconds) of the source.
+    /// Sources that aren't seekable return an error.
+    fn seek(&mut self, _seconds: f64) -> Result<(), ProtocolError> {
+        Err(custom_error(
+            "the current audio source does not support seeking",
+        ))
+    }
+
+    /// Whether this source can start over from the beginning once it ends,
+    /// which is needed to loop it. Seekable sources can by seeking back to the start.
+    fn can_restart(&self) -> bool {
+        self.is_seekable()
+    }
+
+    /// Starts the source over from the beginning.
+    /// This is only called if can_restart returns true.
+    fn restart(&mut self) -> Result<(), ProtocolError> {
+        self.seek(0.0)
+    }
+
+    /// The error that caused the source to terminate, if any.
+    /// This is checked once a read returns None and is passed to the after callback.
+    fn take_error(&mut self) -> Option<ProtocolError> {
+        None
+    }
+
+    /// Returns the source as a Mixer if it is one, so sources can be mixed into it
+    fn as_mixer(&mut self) -> Option<&mut Mixer> {
+        None
+    }
 }
 
 pub struct FFmpegPCMAudio {
     process: Child,
+    frames: Receiver<Vec<u8>>,
+    stderr: Option<thread::JoinHandle<VecDeque<String>>>,
+    channels: u16,
+    frame_length: u16,
+    read_timeout: Duration,
+    prebuffer: usize,
+    // Whether the read ahead has been waited for since FFmpeg was (re)spawned
+    prebuffered: bool,
+    error: Option<ProtocolError>,
+    input: String,
+    before_options: Vec<String>,
+    options: Vec<String>,
+}
+
+/// The input FFmpeg is given when it reads from its stdin
+const PIPE_INPUT: &str = "pipe:0";
+
+fn spawn_ffmpeg(
+    input: &str,
+    before_options: &[String],
+    options: &[String],
+    channels: u16,
+    stdin: Stdio,
+) -> Result<Child, ProtocolError> {
+    let process = Command::new("ffmpeg")
+        .args(before_options)
+        .arg("-i")
+        .arg(input)
+        .args(&["-f", "s16le", "-ar", "48000", "-ac"])
+        .arg(channels.to_string())
+        .args(&["-loglevel", "warning"])
+        .args(options)
+        .arg("pipe:1")
+        .stdin(stdin)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| match e.kind() {
+            ErrorKind::NotFound => ProtocolError::FFmpegNotFound("ffmpeg"),
+            _ => ProtocolError::from(e),
+        })?;
+    Ok(process)
+}
+
+#[derive(Deserialize)]
+struct ProbeOutput {
+    format: Option<ProbeFormat>,
+    #[serde(default)]
+    streams: Vec<ProbeStream>,
+}
+
+#[derive(Deserialize)]
+struct ProbeFormat {
+    duration: Option<String>,
+    bit_rate: Option<String>,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct ProbeStream {
+    codec_type: Option<String>,
+    codec_name: Option<String>,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+}
+
+/// What ffprobe knows about an input. Every field is optional
+/// since streams and containers only report some of them.
+#[derive(Debug, Default)]
+pub struct ProbeInfo {
+    /// The duration in seconds, missing for live streams
+    pub duration: Option<f64>,
+    /// The overall bitrate in bits per second
+    pub bitrate: Option<u64>,
+    /// The codec of the first audio stream
+    pub codec: Option<String>,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+}
+
+/// Looks a tag up regardless of case, since containers differ on "title" and "TITLE"
+fn find_tag(tags: &HashMap<String, String>, name: &str) -> Option<String> {
+    tags.iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.clone())
+}
+
+/// Runs ffprobe on the input, which is anything FFmpegPCMAudio accepts.
+/// This blocks until ffprobe exits so it should be called off the event loop.
+pub fn probe(input: &str) -> Result<ProbeInfo, ProtocolError> {
+    let output = Command::new("ffprobe")
+        .args(&["-v", "error"])
+        .args(&["-show_format", "-show_streams", "-of", "json"])
+        .arg(input)
+        .stdin(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .map_err(|e| match e.kind() {
+            ErrorKind::NotFound => ProtocolError::FFmpegNotFound("ffprobe"),
+            _ => ProtocolError::from(e),
+        })?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let message = format!("ffprobe exited with {}: {}", output.status, stderr.trim());
+        return Err(custom_error(&message));
+    }
+
+    let parsed: ProbeOutput = serde_json::from_slice(&output.stdout)?;
+    let audio = parsed
+        .streams
+        .iter()
+        .find(|stream| stream.codec_type.as_deref() == Some("audio"));
+
+    let mut info = ProbeInfo {
+        codec: audio.and_then(|stream| stream.codec_name.clone()),
+        ..ProbeInfo::default()
+    };
+    if let Some(format) = &parsed.format {
+        info.duration = format.duration.as_ref().and_then(|d| d.parse().ok());
+        info.bitrate = format.bit_rate.as_ref().and_then(|b| b.parse().ok());
+        info.title = find_tag(&format.tags, "title");
+        info.artist = find_tag(&format.tags, "artist");
+    }
+    // Some containers (e.g. Ogg) keep the tags on the stream instead
+    if let Some(stream) = audio {
+        info.title = info.title.or_else(|| find_tag(&stream.tags, "title"));
+        info.artist = info.artist.or_else(|| find_tag(&stream.tags, "artist"));
+    }
+    Ok(info)
+}
+
+/// Buffers a PCM pipe so that a single read from it fetches many frames at once,
+/// rather than making a syscall for every frame.
+pub fn buffered_pcm_reader<R: Read>(reader: R, frame_size: usize) -> BufReader<R> {
+    BufReader::with_capacity(frame_size * READ_BUFFER_FRAMES, reader)
+}
+
+/// Reads frames from FFmpeg's stdout on a separate thread so the player
+/// can wait on them with a timeout instead of blocking on the pipe.
+/// The thread ends when the pipe is closed or the receiver is dropped.
+fn spawn_reader(
+    process: &mut Child,
+    frame_size: usize,
+) -> Result<Receiver<Vec<u8>>, ProtocolError> {
+    let mut stdout = match process.stdout.take() {
+        Some(stdout) => buffered_pcm_reader(stdout, frame_size),
+        None => return Err(custom_error("ffmpeg stdout is not piped")),
+    };
+    let (sender, receiver) = bounded(READ_AHEAD_FRAMES);
+    thread::spawn(move || loop {
+        let mut frame = vec![0u8; frame_size];
+        if stdout.read_exact(&mut frame).is_err() {
+            break;
+        }
+        if sender.send(frame).is_err() {
+            break;
+        }
+    });
+    Ok(receiver)
+}
+
+/// Reads FFmpeg's stderr while keeping the last few lines around,
+/// which are returned once FFmpeg closes the pipe.
+fn spawn_stderr_reader(process: &mut Child) -> Option<thread::JoinHandle<VecDeque<String>>> {
+    let stderr = process.stderr.take()?;
+    Some(thread::spawn(move || {
+        let mut lines = VecDeque::with_capacity(STDERR_TAIL_LINES);
+        for line in BufReader::new(stderr).split(b'\n') {
+            let line = match line {
+                Ok(line) => String::from_utf8_lossy(&line).trim_end().to_owned(),
+                Err(_) => break,
+            };
+            if lines.len() == STDERR_TAIL_LINES {
+                lines.pop_front();
+            }
+            lines.push_back(line);
+        }
+        lines
+    }))
 }
 
 impl FFmpegPCMAudio {
     pub fn new(input: &str) -> Result<Self, ProtocolError> {
-        let process = Command::new("ffmpeg")
-            .arg("-i")
-            .arg(&input)
-            .args(&[
-                "-f",
-                "s16le",
-                "-ar",
-                "48000",
-                "-ac",
-                "2",
-                "-loglevel",
-                "warning",
-                "pipe:1",
-            ])
-            .stdout(Stdio::piped())
-            .spawn()?;
-        Ok(Self { process })
+        Self::with_format(input, &[], &[], CHANNELS, FRAME_LENGTH)
+    }
+
+    /// Same as new except with extra arguments passed to FFmpeg.
+    /// `before_options` are placed before the `-i` flag and `options` after the
+    /// output format, mirroring discord.py's FFmpegPCMAudio.
+    pub fn with_options(
+        input: &str,
+        before_options: &[String],
+        options: &[String],
+    ) -> Result<Self, ProtocolError> {
+        Self::with_format(input, before_options, options, CHANNELS, FRAME_LENGTH)
+    }
+
+    /// Same as with_options except FFmpeg outputs the given number of channels, split
+    /// into frames of the given length in milliseconds. Both have to match the encoder's.
+    pub fn with_format(
+        input: &str,
+        before_options: &[String],
+        options: &[String],
+        channels: u16,
+        frame_length: u16,
+    ) -> Result<Self, ProtocolError> {
+        Self::spawn(
+            input,
+            before_options,
+            options,
+            channels,
+            frame_length,
+            Stdio::inherit(),
+        )
+    }
+
+    /// Feeds FFmpeg the data read from the reader through its stdin rather than
+    /// having it open an input itself, e.g. for audio that's already downloaded.
+    /// The reader is pumped on a separate thread until it's exhausted, at which
+    /// point stdin is closed so FFmpeg sees the end of its input.
+    /// Piped sources can't seek since the data they've read is gone.
+    pub fn from_reader<R>(
+        mut reader: R,
+        before_options: &[String],
+        options: &[String],
+        channels: u16,
+        frame_length: u16,
+    ) -> Result<Self, ProtocolError>
+    where
+        R: Read + Send + 'static,
+    {
+        let mut source = Self::spawn(
+            PIPE_INPUT,
+            before_options,
+            options,
+            channels,
+            frame_length,
+            Stdio::piped(),
+        )?;
+        let mut stdin = match source.process.stdin.take() {
+            Some(stdin) => stdin,
+            None => return Err(custom_error("ffmpeg stdin is not piped")),
+        };
+        // stdout is drained by its own thread so FFmpeg can't stall on a full
+        // output pipe while this thread is stalled on a full input pipe.
+        // Once FFmpeg is killed the write fails with a broken pipe which ends the copy.
+        thread::spawn(move || {
+            // Either way stdin is closed once the copy stops, so FFmpeg sees the end
+            // of its input and the source ends with whatever it has read
+            let _ = std::io::copy(&mut reader, &mut stdin);
+        });
+        Ok(source)
+    }
+
+    fn spawn(
+        input: &str,
+        before_options: &[String],
+        options: &[String],
+        channels: u16,
+        frame_length: u16,
+        stdin: Stdio,
+    ) -> Result<Self, ProtocolError> {
+        let mut process = spawn_ffmpeg(input, before_options, options, channels, stdin)?;
+        let frames = spawn_reader(&mut process, Self::frame_size(channels, frame_length))?;
+        let stderr = spawn_stderr_reader(&mut process);
+        Ok(Self {
+            process,
+            frames,
+            stderr,
+            channels,
+            frame_length,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            prebuffer: DEFAULT_PREBUFFER_FRAMES,
+            prebuffered: false,
+            error: None,
+            input: input.to_owned(),
+            before_options: before_options.to_vec(),
+            options: options.to_vec(),
+        })
+    }
+
+    /// Sets how long to wait for FFmpeg to produce a frame.
+    /// If FFmpeg stalls for longer than this, e.g. on a dead network stream,
+    /// the source terminates with a SourceStalled error.
+    pub fn set_read_timeout(&mut self, timeout: Duration) {
+        self.read_timeout = timeout;
+    }
+
+    /// Sets how many frames FFmpeg has to have read ahead before the first one is
+    /// returned, which absorbs the jitter of network inputs starting up. The read
+    /// ahead keeps filling while playing. This applies again after seeking.
+    pub fn set_prebuffer(&mut self, frames: usize) {
+        self.prebuffer = frames.min(READ_AHEAD_FRAMES);
+    }
+
+    /// Waits until the prebuffer has filled, FFmpeg has exited (so a short input
+    /// doesn't wait for frames that never come) or the read timeout has passed
+    fn wait_for_prebuffer(&mut self) {
+        self.prebuffered = true;
+        let started = Instant::now();
+        while self.frames.len() < self.prebuffer && started.elapsed() < self.read_timeout {
+            if let Ok(Some(_)) = self.process.try_wait() {
+                break;
+            }
+            thread::sleep(PREBUFFER_POLL_INTERVAL);
+        }
+    }
+
+    fn frame_size(channels: u16, frame_length: u16) -> usize {
+        (SAMPLING_RATE / 1000) as usize * frame_length as usize * channels as usize * 2
+    }
+
+    /// Waits for FFmpeg to exit once its output has ended.
+    /// Returns an error with the tail of its stderr if it exited unsuccessfully.
+    fn exit_error(&mut self) -> Option<ProtocolError> {
+        let status = self.process.wait().ok()?;
+        if status.success() {
+            return None;
+        }
+
+        let lines = match self.stderr.take() {
+            Some(thread) => thread.join().unwrap_or_default(),
+            None => VecDeque::new(),
+        };
+        let stderr: Vec<String> = lines.into_iter().collect();
+        Some(ProtocolError::FFmpeg(status.code(), stderr.join("\n")))
     }
 }
 
 impl AudioSource for FFmpegPCMAudio {
     fn read_pcm_frame(&mut self, buffer: &mut [i16]) -> Option<usize> {
-        let stdout = self.process.stdout.as_mut().unwrap();
-        let bytes = unsafe {
-            std::slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut u8, buffer.len() * 2)
-        };
-        stdout.read_exact(bytes).map(|_| buffer.len()).ok()
+        if !self.prebuffered {
+            self.wait_for_prebuffer();
+        }
+        match self.frames.recv_timeout(self.read_timeout) {
+            Ok(frame) => {
+                decode_pcm_le(&frame, buffer);
+                Some(buffer.len())
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                self.error = Some(ProtocolError::SourceStalled(self.read_timeout));
+                None
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                self.error = self.exit_error();
+                None
+            }
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        "ffmpeg"
+    }
+
+    fn is_seekable(&self) -> bool {
+        self.input != PIPE_INPUT
+    }
+
+    /// Seeking respawns the FFmpeg process with `-ss` placed before the input
+    fn seek(&mut self, seconds: f64) -> Result<(), ProtocolError> {
+        if !self.is_seekable() {
+            return Err(custom_error("piped ffmpeg input can't be seeked"));
+        }
+
+        // The old process is stopped first so that if it can't be, the source is left
+        // as it was. One that has already exited, e.g. when looping restarts a source
+        // that played to the end, has nothing to kill.
+        if self.process.try_wait()?.is_none() {
+            self.process.kill()?;
+        }
+        let _ = self.process.wait();
+
+        let mut before_options = vec!["-ss".to_owned(), seconds.to_string()];
+        before_options.extend_from_slice(&self.before_options);
+        let mut process = spawn_ffmpeg(
+            self.input.as_str(),
+            &before_options,
+            &self.options,
+            self.channels,
+            Stdio::inherit(),
+        )?;
+        let frame_size = Self::frame_size(self.channels, self.frame_length);
+        self.frames = spawn_reader(&mut process, frame_size)?;
+        self.stderr = spawn_stderr_reader(&mut process);
+        self.prebuffered = false;
+        self.process = process;
+        Ok(())
     }
 }
 
@@ -95,6 +793,418 @@ impl Drop for FFmpegPCMAudio {
     }
 }
 
+/// An in-memory source of 16-bit stereo 48000Hz little-endian PCM.
+/// This avoids spawning FFmpeg for short sounds that are already decoded.
+pub struct BytesPCMAudio {
+    data: Vec<u8>,
+    offset: usize,
+}
+
+impl BytesPCMAudio {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self { data, offset: 0 }
+    }
+}
+
+impl AudioSource for BytesPCMAudio {
+    fn kind(&self) -> &'static str {
+        "bytes"
+    }
+
+    fn read_pcm_frame(&mut self, buffer: &mut [i16]) -> Option<usize> {
+        if self.offset >= self.data.len() {
+            return None;
+        }
+
+        let end = (self.offset + buffer.len() * 2).min(self.data.len());
+        let chunk = &self.data[self.offset..end];
+        self.offset = end;
+
+        // The final frame might be partial, which is zero-padded
+        decode_pcm_le(chunk, buffer);
+        Some(buffer.len())
+    }
+
+    fn can_restart(&self) -> bool {
+        true
+    }
+
+    fn restart(&mut self) -> Result<(), ProtocolError> {
+        self.offset = 0;
+        Ok(())
+    }
+}
+
+/// Reads pre-encoded Opus audio from a DCA file.
+/// A DCA file starts with the `DCA1` magic, followed by a little-endian i32 length
+/// and a JSON metadata header of that length. The rest of the file is made up of
+/// Opus frames, each prefixed with its length as a little-endian i16.
+pub struct DCAAudio {
+    reader: BufReader<File>,
+}
+
+impl DCAAudio {
+    pub fn new(path: &str) -> Result<Self, ProtocolError> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != b"DCA1" {
+            return Err(custom_error("invalid DCA file (missing DCA1 magic)"));
+        }
+
+        let mut length = [0u8; 4];
+        reader.read_exact(&mut length)?;
+        let length = i32::from_le_bytes(length);
+        if length < 0 {
+            return Err(custom_error("invalid DCA file (negative metadata length)"));
+        }
+
+        let mut metadata = vec![0u8; length as usize];
+        reader.read_exact(&mut metadata)?;
+        // The metadata isn't used but it should at least be valid JSON
+        serde_json::from_slice::<serde_json::Value>(&metadata)?;
+        Ok(Self { reader })
+    }
+}
+
+impl AudioSource for DCAAudio {
+    fn get_type(&self) -> AudioType {
+        AudioType::Opus
+    }
+
+    fn kind(&self) -> &'static str {
+        "dca"
+    }
+
+    fn read_opus_frame(&mut self, buffer: &mut [u8]) -> Option<usize> {
+        let mut length = [0u8; 2];
+        self.reader.read_exact(&mut length).ok()?;
+        let length = i16::from_le_bytes(length);
+        if length <= 0 || length as usize > buffer.len() {
+            return None;
+        }
+
+        let length = length as usize;
+        self.reader.read_exact(&mut buffer[..length]).ok()?;
+        Some(length)
+    }
+}
+
+/// Mixes a stereo PCM source down to mono by averaging each left and right sample.
+/// The encoder always encodes stereo so the mono sample is written to both channels,
+/// which Opus encodes nearly as cheaply as a single channel.
+/// The inner source has to be a PCM source.
+pub struct DownmixMono {
+    inner: Box<dyn AudioSource>,
+}
+
+impl DownmixMono {
+    pub fn new(inner: Box<dyn AudioSource>) -> Self {
+        Self { inner }
+    }
+}
+
+impl AudioSource for DownmixMono {
+    fn read_pcm_frame(&mut self, buffer: &mut [i16]) -> Option<usize> {
+        let size = self.inner.read_pcm_frame(buffer)?;
+        for pair in buffer.chunks_exact_mut(CHANNELS as usize) {
+            let mixed = ((pair[0] as i32 + pair[1] as i32) / 2) as i16;
+            pair[0] = mixed;
+            pair[1] = mixed;
+        }
+        Some(size)
+    }
+
+    fn kind(&self) -> &'static str {
+        self.inner.kind()
+    }
+
+    fn is_seekable(&self) -> bool {
+        self.inner.is_seekable()
+    }
+
+    fn seek(&mut self, seconds: f64) -> Result<(), ProtocolError> {
+        self.inner.seek(seconds)
+    }
+
+    fn can_restart(&self) -> bool {
+        self.inner.can_restart()
+    }
+
+    fn restart(&mut self) -> Result<(), ProtocolError> {
+        self.inner.restart()
+    }
+
+    fn take_error(&mut self) -> Option<ProtocolError> {
+        self.inner.take_error()
+    }
+}
+
+/// Fades from one PCM source to another with a linear gain ramp.
+/// If the outgoing source ends early it is treated as silence for the rest of the
+/// fade. Once the fade is over this just passes through the incoming source.
+pub struct Crossfade {
+    from: Option<Box<dyn AudioSource>>,
+    to: Box<dyn AudioSource>,
+    frames: u32,
+    elapsed: u32,
+    scratch: Vec<i16>,
+}
+
+impl Crossfade {
+    pub fn new(from: Box<dyn AudioSource>, to: Box<dyn AudioSource>, frames: u32) -> Self {
+        Self {
+            from: Some(from),
+            to,
+            frames: frames.max(1),
+            elapsed: 0,
+            scratch: Vec::new(),
+        }
+    }
+}
+
+impl AudioSource for Crossfade {
+    fn read_pcm_frame(&mut self, buffer: &mut [i16]) -> Option<usize> {
+        let size = self.to.read_pcm_frame(buffer)?;
+        if self.elapsed >= self.frames {
+            self.from = None;
+            return Some(size);
+        }
+
+        self.scratch.clear();
+        self.scratch.resize(buffer.len(), 0);
+        if let Some(from) = self.from.as_mut() {
+            if from.read_pcm_frame(&mut self.scratch).is_none() {
+                self.from = None;
+            }
+        }
+
+        let frame_samples = buffer.len() as f32;
+        for (i, (sample, old)) in buffer.iter_mut().zip(self.scratch.iter()).enumerate() {
+            let position = self.elapsed as f32 + i as f32 / frame_samples;
+            let gain = (position / self.frames as f32).min(1.0);
+            *sample = (*sample as f32 * gain + *old as f32 * (1.0 - gain)) as i16;
+        }
+
+        self.elapsed += 1;
+        Some(size)
+    }
+
+    fn kind(&self) -> &'static str {
+        self.to.kind()
+    }
+
+    fn is_seekable(&self) -> bool {
+        self.to.is_seekable()
+    }
+
+    /// Seeking ends the fade and seeks the incoming source
+    fn seek(&mut self, seconds: f64) -> Result<(), ProtocolError> {
+        self.from = None;
+        self.elapsed = self.frames;
+        self.to.seek(seconds)
+    }
+
+    fn take_error(&mut self) -> Option<ProtocolError> {
+        self.to.take_error()
+    }
+}
+
+/// A PCM source mixed into a Mixer along with its gain
+struct MixTrack {
+    source: Box<dyn AudioSource>,
+    gain: f32,
+}
+
+/// Mixes several PCM sources into one, e.g. sound effects over music.
+/// Each frame reads a frame from every source, scales it by the source's gain
+/// and sums them, clamping the result. Sources that come up short are padded
+/// with silence and sources that end are removed. The mixer ends once it has
+/// no sources left.
+pub struct Mixer {
+    tracks: Vec<MixTrack>,
+    mix: Vec<f32>,
+    scratch: Vec<i16>,
+    // The first error a source ended with, reported once the mix ends
+    error: Option<ProtocolError>,
+}
+
+impl Mixer {
+    pub fn new() -> Self {
+        Self {
+            tracks: Vec::new(),
+            mix: Vec::new(),
+            scratch: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// Adds a source to the mix, starting with the next frame.
+    /// Opus sources can't be mixed so they're rejected.
+    pub fn add(&mut self, source: Box<dyn AudioSource>, gain: f32) -> Result<(), ProtocolError> {
+        if let AudioType::Opus = source.get_type() {
+            return Err(custom_error("opus sources can't be mixed"));
+        }
+        self.tracks.push(MixTrack { source, gain });
+        Ok(())
+    }
+
+    /// The number of sources being mixed
+    pub fn len(&self) -> usize {
+        self.tracks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tracks.is_empty()
+    }
+}
+
+impl Default for Mixer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AudioSource for Mixer {
+    fn read_pcm_frame(&mut self, buffer: &mut [i16]) -> Option<usize> {
+        if self.tracks.is_empty() {
+            return None;
+        }
+
+        self.mix.clear();
+        self.mix.resize(buffer.len(), 0.0);
+        let mix = &mut self.mix;
+        let scratch = &mut self.scratch;
+        let mut index = 0;
+        while index < self.tracks.len() {
+            scratch.clear();
+            scratch.resize(buffer.len(), 0);
+            let track = &mut self.tracks[index];
+            if track.source.read_pcm_frame(scratch).is_none() {
+                let error = track.source.take_error();
+                if self.error.is_none() {
+                    self.error = error;
+                }
+                self.tracks.remove(index);
+                continue;
+            }
+            for (total, sample) in mix.iter_mut().zip(scratch.iter()) {
+                *total += *sample as f32 * track.gain;
+            }
+            index += 1;
+        }
+
+        if self.tracks.is_empty() {
+            return None;
+        }
+
+        for (sample, total) in buffer.iter_mut().zip(mix.iter()) {
+            *sample = total.max(i16::MIN as f32).min(i16::MAX as f32) as i16;
+        }
+        Some(buffer.len())
+    }
+
+    fn kind(&self) -> &'static str {
+        "mixer"
+    }
+
+    fn take_error(&mut self) -> Option<ProtocolError> {
+        self.error.take()
+    }
+
+    fn as_mixer(&mut self) -> Option<&mut Mixer> {
+        Some(self)
+    }
+}
+
+/// How quickly the limiter reduces the gain once a peak goes over the threshold
+const LIMITER_ATTACK_MS: f32 = 1.0;
+/// How quickly the limiter lets the gain recover once the peaks are gone
+const LIMITER_RELEASE_MS: f32 = 100.0;
+
+/// Per sample smoothing coefficient of an envelope that settles in the given time
+fn envelope_coefficient(milliseconds: f32) -> f32 {
+    let samples = milliseconds * SAMPLING_RATE as f32 / 1000.0 * CHANNELS as f32;
+    1.0 - (-1.0 / samples).exp()
+}
+
+/// A peak limiter that keeps the samples of a PCM source below a threshold.
+/// It follows the peak level with a fast attack and a slow release and scales
+/// the samples down by however much that peak is over the threshold. Anything
+/// that still gets through before the gain catches up is clipped.
+pub struct Limiter {
+    inner: Box<dyn AudioSource>,
+    threshold: f32,
+    envelope: f32,
+    attack: f32,
+    release: f32,
+}
+
+impl Limiter {
+    /// The threshold is a fraction of full scale, between 0 and 1
+    pub fn new(inner: Box<dyn AudioSource>, threshold: f32) -> Self {
+        Self {
+            inner,
+            threshold: threshold.max(0.0).min(1.0) * i16::MAX as f32,
+            envelope: 0.0,
+            attack: envelope_coefficient(LIMITER_ATTACK_MS),
+            release: envelope_coefficient(LIMITER_RELEASE_MS),
+        }
+    }
+}
+
+impl AudioSource for Limiter {
+    fn read_pcm_frame(&mut self, buffer: &mut [i16]) -> Option<usize> {
+        let size = self.inner.read_pcm_frame(buffer)?;
+        for sample in buffer.iter_mut() {
+            let level = (*sample as f32).abs();
+            let coefficient = if level > self.envelope {
+                self.attack
+            } else {
+                self.release
+            };
+            self.envelope += (level - self.envelope) * coefficient;
+
+            let gain = if self.envelope > self.threshold {
+                self.threshold / self.envelope
+            } else {
+                1.0
+            };
+            let limited = (*sample as f32 * gain)
+                .max(-self.threshold)
+                .min(self.threshold);
+            *sample = limited as i16;
+        }
+        Some(size)
+    }
+
+    fn kind(&self) -> &'static str {
+        self.inner.kind()
+    }
+
+    fn is_seekable(&self) -> bool {
+        self.inner.is_seekable()
+    }
+
+    fn seek(&mut self, seconds: f64) -> Result<(), ProtocolError> {
+        self.envelope = 0.0;
+        self.inner.seek(seconds)
+    }
+
+    fn can_restart(&self) -> bool {
+        self.inner.can_restart()
+    }
+
+    fn restart(&mut self) -> Result<(), ProtocolError> {
+        self.envelope = 0.0;
+        self.inner.restart()
+    }
+
+    fn take_error(&mut self) -> Option<ProtocolError> {
+        self.inner.take_error()
+    }
+}
+
 /// In order to efficiently manage a buffer we need to prepend some bytes during
 /// packet creation, so a specific offset of that buffer has to modified
 /// This type is a wrapper that allows me to do that.
@@ -156,6 +1266,67 @@ impl Buffer for InPlaceBuffer<'_> {
     }
 }
 
+/// The cipher for the negotiated encryption mode.
+/// Both ciphers take a 32 byte key and a 24 byte nonce.
+pub enum VoiceCipher {
+    XSalsa20Poly1305(XSalsa20Poly1305),
+    XChaCha20Poly1305(XChaCha20Poly1305),
+}
+
+impl VoiceCipher {
+    pub fn new(mode: EncryptionMode, key: &[u8; 32]) -> Self {
+        let key = GenericArray::from_slice(key);
+        match mode {
+            EncryptionMode::AeadXChaCha20Poly1305RtpSize => {
+                VoiceCipher::XChaCha20Poly1305(XChaCha20Poly1305::new(key))
+            }
+            _ => VoiceCipher::XSalsa20Poly1305(XSalsa20Poly1305::new(key)),
+        }
+    }
+
+    /// Note that XSalsa20Poly1305 does not support associated data, so it has to be empty
+    pub fn encrypt_in_place(
+        &self,
+        nonce: &[u8; 24],
+        associated_data: &[u8],
+        buffer: &mut dyn Buffer,
+    ) -> Result<(), xsalsa20poly1305::aead::Error> {
+        let nonce = GenericArray::from_slice(nonce);
+        match self {
+            VoiceCipher::XSalsa20Poly1305(c) => c.encrypt_in_place(nonce, associated_data, buffer),
+            VoiceCipher::XChaCha20Poly1305(c) => c.encrypt_in_place(nonce, associated_data, buffer),
+        }
+    }
+
+    pub fn decrypt_in_place(
+        &self,
+        nonce: &[u8; 24],
+        associated_data: &[u8],
+        buffer: &mut dyn Buffer,
+    ) -> Result<(), xsalsa20poly1305::aead::Error> {
+        let nonce = GenericArray::from_slice(nonce);
+        match self {
+            VoiceCipher::XSalsa20Poly1305(c) => c.decrypt_in_place(nonce, associated_data, buffer),
+            VoiceCipher::XChaCha20Poly1305(c) => c.decrypt_in_place(nonce, associated_data, buffer),
+        }
+    }
+}
+
+/// Encrypts the Opus data in the buffer and appends whatever the receiver needs to decrypt it.
+/// Takes the cipher, the lite nonce counter and the RTP header of the packet.
+pub(crate) type Encrypter =
+    fn(&VoiceCipher, u32, &[u8], &mut dyn Buffer) -> Result<(), xsalsa20poly1305::aead::Error>;
+
+/// Returns the function that frames packets for the given mode
+pub(crate) fn encrypter_for(mode: EncryptionMode) -> Encrypter {
+    match mode {
+        EncryptionMode::XSalsa20Poly1305 => encrypt_xsalsa20_poly1305,
+        EncryptionMode::XSalsa20Poly1305Suffix => encrypt_xsalsa20_poly1305_suffix,
+        EncryptionMode::XSalsa20Poly1305Lite => encrypt_xsalsa20_poly1305_lite,
+        EncryptionMode::AeadXChaCha20Poly1305RtpSize => encrypt_aead_rtpsize,
+    }
+}
+
 /// The maximum buffer size. 1275 is the maximum size  of an ideal Opus frame packet.
 /// 24 bytes is for the nonce when constructing the audio packet
 /// 12 bytes is for the header.
@@ -163,184 +1334,721 @@ impl Buffer for InPlaceBuffer<'_> {
 /// 16 bytes for the xsalsa20poly1305 tag
 /// 12 extra bytes of space
 pub const MAX_BUFFER_SIZE: usize = 1275 + 24 + 12 + 24 + 16 + 12;
+/// The largest Opus packet there can be, which is what raw frames are limited to
+pub const MAX_OPUS_FRAME_SIZE: usize = 1275;
 pub const BUFFER_OFFSET: usize = 12;
+/// The most any encryption mode adds after the Opus data, a 16 byte tag and a 24 byte nonce
+pub const PACKET_OVERHEAD: usize = 16 + 24;
+/// The most Opus data that fits in the buffer along with the header and PACKET_OVERHEAD
+pub const MAX_PAYLOAD_SIZE: usize = MAX_BUFFER_SIZE - BUFFER_OFFSET - PACKET_OVERHEAD;
 type PacketBuffer = [u8; MAX_BUFFER_SIZE];
 
-struct AudioEncoder {
+pub struct AudioEncoder {
     opus: audiopus::coder::Encoder,
-    cipher: XSalsa20Poly1305,
+    cipher: VoiceCipher,
     sequence: u16,
     timestamp: u32,
     lite_nonce: u32,
     ssrc: u32,
-    pcm_buffer: [i16; 1920],
+    bitrate: i32,
+    pcm_buffer: [i16; MAX_FRAME_SAMPLES],
+    // The number of samples in pcm_buffer that make up a frame, which depends on
+    // the channels and the frame length
+    pcm_samples: usize,
+    samples_per_frame: u32,
+    channels: u16,
+    // How long SendPolicy::Block keeps retrying a packet, one frame's worth
+    frame_duration: Duration,
     // It's a re-used buffer that is used for multiple things
     // 1) The opus encoding result goes here
     // 2) The cipher is done in-place
     // 3) The final packet to send is through this buffer as well
     buffer: PacketBuffer,
-    encrypter: fn(
-        &XSalsa20Poly1305,
-        u32,
-        &[u8],
-        &mut dyn Buffer,
-    ) -> Result<(), xsalsa20poly1305::aead::Error>,
+    encrypter: Encrypter,
+    // How long encrypting the last packet sent took
+    encrypt_time: Duration,
+}
+
+fn encrypt_xsalsa20_poly1305(
+    cipher: &VoiceCipher,
+    _lite: u32,
+    header: &[u8],
+    data: &mut dyn Buffer,
+) -> Result<(), xsalsa20poly1305::aead::Error> {
+    let mut nonce: [u8; 24] = [0; 24];
+    nonce[0..12].copy_from_slice(&header);
+
+    cipher.encrypt_in_place(&nonce, b"", data)?;
+    data.extend_from_slice(&nonce)?;
+    Ok(())
+}
+
+fn encrypt_xsalsa20_poly1305_suffix(
+    cipher: &VoiceCipher,
+    _lite: u32,
+    _header: &[u8],
+    data: &mut dyn Buffer,
+) -> Result<(), xsalsa20poly1305::aead::Error> {
+    let mut nonce: [u8; 24] = [0; 24];
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    cipher.encrypt_in_place(&nonce, b"", data)?;
+    data.extend_from_slice(&nonce)?;
+    Ok(())
+}
+
+fn encrypt_xsalsa20_poly1305_lite(
+    cipher: &VoiceCipher,
+    lite: u32,
+    _header: &[u8],
+    data: &mut dyn Buffer,
+) -> Result<(), xsalsa20poly1305::aead::Error> {
+    let mut nonce: [u8; 24] = [0; 24];
+    nonce[0..4].copy_from_slice(&lite.to_be_bytes());
+
+    cipher.encrypt_in_place(&nonce, b"", data)?;
+    data.extend_from_slice(&nonce[0..4])?;
+    Ok(())
+}
+
+/// The rtpsize modes only encrypt the payload, the RTP header (including the
+/// extension header if there is one) is authenticated as associated data.
+/// The nonce is the same incrementing 4 byte counter as the lite mode.
+fn encrypt_aead_rtpsize(
+    cipher: &VoiceCipher,
+    lite: u32,
+    header: &[u8],
+    data: &mut dyn Buffer,
+) -> Result<(), xsalsa20poly1305::aead::Error> {
+    let mut nonce: [u8; 24] = [0; 24];
+    nonce[0..4].copy_from_slice(&lite.to_be_bytes());
+
+    cipher.encrypt_in_place(&nonce, header, data)?;
+    data.extend_from_slice(&nonce[0..4])?;
+    Ok(())
+}
+
+impl AudioEncoder {
+    fn from_protocol(
+        protocol: &DiscordVoiceProtocol,
+        bitrate: i32,
+        options: &EncoderOptions,
+    ) -> Result<Self, ProtocolError> {
+        Self::new(
+            protocol.encryption,
+            &protocol.secret_key,
+            protocol.ssrc,
+            bitrate,
+            options,
+        )
+    }
+
+    /// Creates an encoder without a voice connection, e.g. for benchmarking
+    pub fn new(
+        encryption: EncryptionMode,
+        secret_key: &[u8; 32],
+        ssrc: u32,
+        bitrate: i32,
+        options: &EncoderOptions,
+    ) -> Result<Self, ProtocolError> {
+        let mut encoder = audiopus::coder::Encoder::new(
+            audiopus::SampleRate::Hz48000,
+            options.channels,
+            audiopus::Application::Audio,
+        )?;
+
+        encoder.set_bitrate(audiopus::Bitrate::BitsPerSecond(bitrate))?;
+        options.apply(&mut encoder)?;
+
+        let cipher = VoiceCipher::new(encryption, secret_key);
+        let encrypter = encrypter_for(encryption);
+
+        Ok(Self {
+            opus: encoder,
+            cipher,
+            encrypter,
+            encrypt_time: Duration::from_millis(0),
+            sequence: 0,
+            timestamp: 0,
+            lite_nonce: 0,
+            ssrc,
+            bitrate,
+            pcm_buffer: [0i16; MAX_FRAME_SAMPLES],
+            pcm_samples: options.frame_samples(),
+            samples_per_frame: options.samples_per_frame(),
+            channels: options.channel_count(),
+            frame_duration: options.frame_duration(),
+            buffer: [0; MAX_BUFFER_SIZE],
+        })
+    }
+
+    /// Carries over the RTP state of the encoder this one replaces so the
+    /// receiving end sees a continuous stream.
+    fn continue_from(&mut self, previous: &AudioEncoder) {
+        self.sequence = previous.sequence;
+        self.timestamp = previous.timestamp;
+        self.lite_nonce = previous.lite_nonce;
+    }
+
+    /// Switches to a new secret key (and encryption mode) in place, e.g. when the
+    /// key is rotated mid-session. Unlike building a new encoder the Opus state,
+    /// sequence and timestamp are untouched so the stream carries on without a gap.
+    pub fn rekey(&mut self, encryption: EncryptionMode, secret_key: &[u8; 32]) {
+        self.cipher = VoiceCipher::new(encryption, secret_key);
+        self.encrypter = encrypter_for(encryption);
+    }
+
+    /// The PCM frame that encode_pcm_buffer encodes
+    pub fn pcm_buffer_mut(&mut self) -> &mut [i16] {
+        &mut self.pcm_buffer[..self.pcm_samples]
+    }
+
+    /// Formulates the audio packet.
+    /// By the time this function is called, the buffer should have the opus data
+    /// already loaded at buffer[BUFFER_OFFSET..]
+    /// Takes everything after BUFFER_OFFSET + `size` and encrypts it
+    pub fn prepare_packet(&mut self, size: usize) -> Result<usize, xsalsa20poly1305::aead::Error> {
+        let mut header = [0u8; BUFFER_OFFSET];
+        header[0] = 0x80;
+        header[1] = 0x78;
+        header[2..4].copy_from_slice(&self.sequence.to_be_bytes());
+        header[4..8].copy_from_slice(&self.timestamp.to_be_bytes());
+        header[8..BUFFER_OFFSET].copy_from_slice(&self.ssrc.to_be_bytes());
+        self.buffer[0..BUFFER_OFFSET].copy_from_slice(&header);
+
+        let mut buffer = InPlaceBuffer::new(&mut self.buffer[BUFFER_OFFSET..], size);
+        (self.encrypter)(&self.cipher, self.lite_nonce, &header, &mut buffer)?;
+        self.lite_nonce = self.lite_nonce.wrapping_add(1);
+        Ok(buffer.len())
+    }
+
+    /// Reads the settings back from the Opus encoder itself
+    pub fn info(&self) -> Result<EncoderInfo, audiopus::error::Error> {
+        let bitrate = match self.opus.bitrate()? {
+            audiopus::Bitrate::BitsPerSecond(bitrate) => bitrate,
+            _ => self.bitrate,
+        };
+        let bitrate_mode = if !self.opus.vbr()? {
+            BitrateMode::Cbr
+        } else if self.opus.vbr_constraint()? {
+            BitrateMode::ConstrainedVbr
+        } else {
+            BitrateMode::Vbr
+        };
+        Ok(EncoderInfo {
+            bitrate,
+            fec: self.opus.inband_fec()?,
+            packet_loss_perc: self.opus.packet_loss_perc()?,
+            signal: self.opus.signal()?,
+            bandwidth: self.opus.bandwidth()?,
+            bitrate_mode,
+        })
+    }
+
+    /// Changes the bitrate of the live encoder
+    fn set_bitrate(&mut self, bitrate: i32) -> Result<(), audiopus::error::Error> {
+        self.opus
+            .set_bitrate(audiopus::Bitrate::BitsPerSecond(bitrate))?;
+        self.bitrate = bitrate;
+        Ok(())
+    }
+
+    pub fn encode_pcm_buffer(&mut self) -> Result<usize, audiopus::error::Error> {
+        // Long frames at a high bitrate can come close to filling the buffer, so Opus is
+        // only given as much of it as leaves room for the encryption to be appended
+        self.opus.encode(
+            &self.pcm_buffer[..self.pcm_samples],
+            &mut self.buffer[BUFFER_OFFSET..BUFFER_OFFSET + MAX_PAYLOAD_SIZE],
+        )
+    }
+
+    /// Sends already opus encoded data over the wire.
+    /// The packet goes through the packet loss simulation if one is given.
+    /// Returns false if the packet was dropped because the socket would block.
+    fn send_opus_packet(
+        &mut self,
+        socket: &UdpSocket,
+        addr: &std::net::SocketAddr,
+        size: usize,
+        policy: SendPolicy,
+        simulation: Option<&mut PacketLossSimulation>,
+    ) -> Result<bool, ProtocolError> {
+        if size > MAX_PAYLOAD_SIZE {
+            return Err(custom_error(&format!(
+                "opus frame is {} bytes, more than the {} that can be sent",
+                size, MAX_PAYLOAD_SIZE
+            )));
+        }
+
+        self.sequence = self.sequence.wrapping_add(1);
+        let started = Instant::now();
+        let size = BUFFER_OFFSET + self.prepare_packet(size)?;
+        self.encrypt_time = started.elapsed();
+        // println!("Sending buffer: {:?}", &self.buffer[0..size]);
+        let packet = &self.buffer[0..size];
+        let sent = match simulation {
+            Some(simulation) => {
+                simulation.send(socket, addr, packet, policy, self.frame_duration)?
+            }
+            None => send_packet(socket, addr, packet, policy, self.frame_duration)?,
+        };
+
+        if !sent {
+            println!(
+                "A packet has been dropped (seq: {}, timestamp: {})",
+                &self.sequence, &self.timestamp
+            );
+            return Ok(false);
+        }
+
+        self.timestamp = self.timestamp.wrapping_add(self.samples_per_frame);
+        Ok(true)
+    }
+
+    /// Accounts for a frame that isn't sent because it's silent.
+    /// Only the timestamp moves on, so the receiver sees the gap as time passing
+    /// rather than packets going missing.
+    fn skip_frame(&mut self) {
+        self.timestamp = self.timestamp.wrapping_add(self.samples_per_frame);
+    }
+}
+
+/// Sends raw Opus frames while no player is running. While one is, frames go through
+/// `AudioPlayer::send_raw_opus` instead so the two don't send the same sequence numbers.
+/// The encoder is kept between frames so the sequence and timestamp carry on, and is
+/// rebuilt if the secret key or SSRC change.
+pub struct RawOpusSender {
+    encoder: AudioEncoder,
+    key_generation: u64,
+}
+
+impl RawOpusSender {
+    pub fn new(
+        protocol: &DiscordVoiceProtocol,
+        options: &EncoderOptions,
+    ) -> Result<Self, ProtocolError> {
+        Ok(Self {
+            encoder: AudioEncoder::from_protocol(protocol, DEFAULT_BITRATE, options)?,
+            key_generation: protocol.clone_state().key_generation(),
+        })
+    }
+
+    /// Encrypts and sends a single Opus frame. Speaking isn't sent, so the caller has
+    /// to have done that for the frame to be played.
+    /// Returns false if the packet was dropped because the socket would block.
+    pub fn send(
+        &mut self,
+        protocol: &DiscordVoiceProtocol,
+        options: &EncoderOptions,
+        frame: &[u8],
+    ) -> Result<bool, ProtocolError> {
+        if frame.len() > MAX_OPUS_FRAME_SIZE {
+            return Err(custom_error(
+                "opus frame is larger than the largest opus packet",
+            ));
+        }
+
+        let key_generation = protocol.clone_state().key_generation();
+        if protocol.ssrc != self.encoder.ssrc {
+            let mut rebuilt = AudioEncoder::from_protocol(protocol, DEFAULT_BITRATE, options)?;
+            rebuilt.continue_from(&self.encoder);
+            self.encoder = rebuilt;
+            self.key_generation = key_generation;
+        } else if key_generation != self.key_generation {
+            self.encoder
+                .rekey(protocol.encryption, &protocol.secret_key);
+            self.key_generation = key_generation;
+        }
+
+        // The socket is replaced when reconnecting so it's fetched every time
+        let socket = protocol.clone_socket()?;
+        let addr = socket.peer_addr()?;
+        self.encoder.buffer[BUFFER_OFFSET..BUFFER_OFFSET + frame.len()].copy_from_slice(frame);
+        self.encoder
+            .send_opus_packet(&socket, &addr, frame.len(), SendPolicy::Drop, None)
+    }
+}
+
+/// Sends a packet, returning false if it had to be dropped because the socket would block.
+/// SendPolicy::Block retries for up to the frame duration given.
+fn send_packet(
+    socket: &UdpSocket,
+    addr: &std::net::SocketAddr,
+    packet: &[u8],
+    policy: SendPolicy,
+    frame_duration: Duration,
+) -> Result<bool, ProtocolError> {
+    let started = Instant::now();
+    loop {
+        match socket.send_to(packet, addr) {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                if policy == SendPolicy::Drop {
+                    return Ok(false);
+                }
+                // Retrying for too long would hold up every frame after this one
+                if started.elapsed() >= frame_duration {
+                    return Ok(false);
+                }
+                thread::sleep(SEND_RETRY_INTERVAL);
+            }
+            Err(e) => return Err(ProtocolError::from(e)),
+            Ok(_) => return Ok(true),
+        }
+    }
+}
+
+/// Deliberately drops and reorders outgoing packets, for testing how receivers
+/// (jitter buffers, FEC) cope with a bad network. This is for debugging only,
+/// the player doesn't have one unless it is explicitly enabled.
+/// The random number generator is seeded so a run can be reproduced.
+pub struct PacketLossSimulation {
+    drop_chance: f64,
+    reorder_chance: f64,
+    rng: StdRng,
+    // A packet held back to be sent after the next one, empty if there is none
+    held: Vec<u8>,
+}
+
+impl PacketLossSimulation {
+    /// The chances are between 0 and 1 and shouldn't add up to more than 1
+    pub fn new(drop_chance: f64, reorder_chance: f64, seed: u64) -> Self {
+        Self {
+            drop_chance,
+            reorder_chance,
+            rng: StdRng::seed_from_u64(seed),
+            held: Vec::with_capacity(MAX_BUFFER_SIZE),
+        }
+    }
+
+    /// Drops the packet, holds it back to swap it with the next one or sends it.
+    /// Dropped and held packets count as sent since the loss is intentional.
+    fn send(
+        &mut self,
+        socket: &UdpSocket,
+        addr: &std::net::SocketAddr,
+        packet: &[u8],
+        policy: SendPolicy,
+        frame_duration: Duration,
+    ) -> Result<bool, ProtocolError> {
+        let roll: f64 = self.rng.gen();
+        if roll < self.drop_chance {
+            return Ok(true);
+        }
+
+        if self.held.is_empty() && roll < self.drop_chance + self.reorder_chance {
+            self.held.extend_from_slice(packet);
+            return Ok(true);
+        }
+
+        let sent = send_packet(socket, addr, packet, policy, frame_duration)?;
+        if !self.held.is_empty() {
+            send_packet(socket, addr, &self.held, policy, frame_duration)?;
+            self.held.clear();
+        }
+        Ok(sent)
+    }
+}
+
+/// Paces outgoing frames against a fixed epoch.
+/// The send time of each frame is `epoch + frames * frame_duration`, so time spent
+/// reading, encoding or sending doesn't accumulate as drift. When behind, the
+/// sleeps are skipped until the clock has caught up.
+pub struct FrameClock {
+    epoch: Instant,
+    frames: u32,
+    frame_duration: Duration,
 }
 
-fn encrypt_xsalsa20_poly1305(
-    cipher: &XSalsa20Poly1305,
-    _lite: u32,
-    header: &[u8],
-    data: &mut dyn Buffer,
-) -> Result<(), xsalsa20poly1305::aead::Error> {
-    let mut nonce: [u8; 24] = [0; 24];
-    nonce[0..12].copy_from_slice(&header);
+impl FrameClock {
+    /// If the clock falls further behind than this (e.g. the source stalled) it is
+    /// reset rather than sending the missed frames as a burst.
+    const MAX_CATCH_UP: Duration = Duration::from_millis(200);
 
-    cipher.encrypt_in_place(GenericArray::from_slice(&nonce), b"", data)?;
-    data.extend_from_slice(&nonce)?;
-    Ok(())
-}
+    pub fn new(frame_duration: Duration, now: Instant) -> Self {
+        Self {
+            epoch: now,
+            frames: 0,
+            frame_duration,
+        }
+    }
 
-fn encrypt_xsalsa20_poly1305_suffix(
-    cipher: &XSalsa20Poly1305,
-    _lite: u32,
-    _header: &[u8],
-    data: &mut dyn Buffer,
-) -> Result<(), xsalsa20poly1305::aead::Error> {
-    let mut nonce: [u8; 24] = [0; 24];
-    rand::thread_rng().fill_bytes(&mut nonce);
+    /// Restarts pacing from now, e.g. after being paused or reconnecting
+    pub fn reset(&mut self, now: Instant) {
+        self.epoch = now;
+        self.frames = 0;
+    }
 
-    cipher.encrypt_in_place(GenericArray::from_slice(&nonce), b"", data)?;
-    data.extend_from_slice(&nonce)?;
-    Ok(())
+    /// The ideal send time of the next frame
+    pub fn deadline(&self) -> Instant {
+        self.epoch + self.frame_duration * self.frames
+    }
+
+    /// Records that a frame was sent and returns how long to wait before the next one
+    pub fn tick(&mut self, now: Instant) -> Duration {
+        self.frames += 1;
+        let deadline = self.deadline();
+        if now.saturating_duration_since(deadline) > Self::MAX_CATCH_UP {
+            self.reset(now);
+            return Duration::from_millis(0);
+        }
+        deadline.saturating_duration_since(now)
+    }
 }
 
-fn encrypt_xsalsa20_poly1305_lite(
-    cipher: &XSalsa20Poly1305,
-    lite: u32,
-    _header: &[u8],
-    data: &mut dyn Buffer,
-) -> Result<(), xsalsa20poly1305::aead::Error> {
-    let mut nonce: [u8; 24] = [0; 24];
-    nonce[0..4].copy_from_slice(&lite.to_be_bytes());
+type Protocol = Arc<Mutex<DiscordVoiceProtocol>>;
+type Source = Arc<Mutex<Box<dyn AudioSource>>>;
+type Queue = Arc<Mutex<VecDeque<Box<dyn AudioSource>>>>;
+type Bitrate = Arc<AtomicI32>;
+type RawFrames = Arc<Mutex<VecDeque<Vec<u8>>>>;
+type SharedEncoderInfo = Arc<Mutex<Option<EncoderInfo>>>;
 
-    cipher.encrypt_in_place(GenericArray::from_slice(&nonce), b"", data)?;
-    data.extend_from_slice(&nonce[0..4])?;
-    Ok(())
+/// Builds an encoder for the protocol's current session and clones its voice socket,
+/// returning them along with the address of the voice server the socket sends to
+fn connect_output(
+    proto: &DiscordVoiceProtocol,
+    bitrate: i32,
+    options: &EncoderOptions,
+) -> Result<(AudioEncoder, UdpSocket, std::net::SocketAddr), ProtocolError> {
+    let encoder = AudioEncoder::from_protocol(proto, bitrate, options)?;
+    let socket = proto.clone_socket()?;
+    let addr = socket.peer_addr()?;
+    Ok((encoder, socket, addr))
 }
 
-impl AudioEncoder {
-    fn from_protocol(protocol: &DiscordVoiceProtocol) -> Result<Self, ProtocolError> {
-        let mut encoder = audiopus::coder::Encoder::new(
-            audiopus::SampleRate::Hz48000,
-            audiopus::Channels::Stereo,
-            audiopus::Application::Audio,
-        )?;
+/// Records the settings of the player's encoder so they can be checked from other threads
+fn publish_encoder_info(encoder: &AudioEncoder, info: &Mutex<Option<EncoderInfo>>) {
+    // The settings are left unknown if they can't be read back
+    if let Ok(current) = encoder.info() {
+        *info.lock() = Some(current);
+    }
+}
 
-        encoder.set_bitrate(audiopus::Bitrate::BitsPerSecond(128000))?;
-        encoder.enable_inband_fec()?;
-        encoder.set_packet_loss_perc(15)?;
-        encoder.set_bandwidth(audiopus::Bandwidth::Fullband)?;
-        encoder.set_signal(audiopus::Signal::Auto)?;
+/// Counts the packets sent by a player. A rising number of dropped packets
+/// means the socket's send buffer is full, i.e. the network can't keep up.
+#[derive(Default)]
+pub struct PacketStats {
+    pub sent: AtomicU64,
+    pub dropped: AtomicU64,
+}
+pub type PacketLoss = Arc<Mutex<Option<PacketLossSimulation>>>;
 
-        let key = GenericArray::clone_from_slice(&protocol.secret_key);
-        let cipher = XSalsa20Poly1305::new(&key);
+/// How many frames can be waiting for a PCM tap before new ones are dropped, one second
+/// of audio at 20ms frames
+pub const PCM_TAP_CAPACITY: usize = 50;
 
-        let encrypter = match &protocol.encryption {
-            EncryptionMode::XSalsa20Poly1305 => encrypt_xsalsa20_poly1305,
-            EncryptionMode::XSalsa20Poly1305Suffix => encrypt_xsalsa20_poly1305_suffix,
-            EncryptionMode::XSalsa20Poly1305Lite => encrypt_xsalsa20_poly1305_lite,
-        };
+/// Where a copy of every PCM frame is sent before it's encoded, if anywhere
+pub type PcmTap = Arc<Mutex<Option<Sender<Vec<u8>>>>>;
 
-        Ok(Self {
-            opus: encoder,
-            cipher,
-            encrypter,
-            sequence: 0,
-            timestamp: 0,
-            lite_nonce: 0,
-            ssrc: protocol.ssrc,
-            pcm_buffer: [0i16; 1920],
-            buffer: [0; MAX_BUFFER_SIZE],
-        })
+/// Hands a copy of the frame to the tap without waiting. The frame is dropped if
+/// the tap is full so a slow reader can't hold up the player, and the tap is
+/// removed once nothing is reading from it.
+fn tap_pcm(tap: &PcmTap, pcm: &[i16]) {
+    let mut guard = tap.lock();
+    if let Some(sender) = guard.as_ref() {
+        if let Err(TrySendError::Disconnected(_)) = sender.try_send(encode_pcm_le(pcm)) {
+            *guard = None;
+        }
     }
+}
 
-    /// Formulates the audio packet.
-    /// By the time this function is called, the buffer should have the opus data
-    /// already loaded at buffer[BUFFER_OFFSET..]
-    /// Takes everything after BUFFER_OFFSET + `size` and encrypts it
-    fn prepare_packet(&mut self, size: usize) -> Result<usize, xsalsa20poly1305::aead::Error> {
-        let mut header = [0u8; BUFFER_OFFSET];
-        header[0] = 0x80;
-        header[1] = 0x78;
-        header[2..4].copy_from_slice(&self.sequence.to_be_bytes());
-        header[4..8].copy_from_slice(&self.timestamp.to_be_bytes());
-        header[8..BUFFER_OFFSET].copy_from_slice(&self.ssrc.to_be_bytes());
-        self.buffer[0..BUFFER_OFFSET].copy_from_slice(&header);
+/// Where the player thread spends its time, added up over every frame sent
+#[derive(Default)]
+pub struct PerformanceStats {
+    pub frames: AtomicU64,
+    pub encode_nanos: AtomicU64,
+    pub encrypt_nanos: AtomicU64,
+    pub sleep_nanos: AtomicU64,
+    /// Frames that were only ready once their send time had already passed.
+    /// If this keeps going up the host can't keep up with encoding in real time.
+    pub late_frames: AtomicU64,
+    /// Frames that failed to encode and were skipped
+    pub encode_errors: AtomicU64,
+}
 
-        let mut buffer = InPlaceBuffer::new(&mut self.buffer[BUFFER_OFFSET..], size);
-        (self.encrypter)(&self.cipher, self.lite_nonce, &header, &mut buffer)?;
-        self.lite_nonce = self.lite_nonce.wrapping_add(1);
-        Ok(buffer.len())
+impl PerformanceStats {
+    fn add(counter: &AtomicU64, duration: Duration) {
+        counter.fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
     }
 
-    fn encode_pcm_buffer(&mut self) -> Result<usize, audiopus::error::Error> {
-        self.opus.encode(&self.pcm_buffer, &mut self.buffer[BUFFER_OFFSET..])
+    /// The average of a counter per frame, in microseconds
+    pub fn average_micros(&self, counter: &AtomicU64) -> f64 {
+        let frames = self.frames.load(Ordering::Relaxed);
+        if frames == 0 {
+            return 0.0;
+        }
+        counter.load(Ordering::Relaxed) as f64 / frames as f64 / 1000.0
     }
+}
 
-    /// Sends already opus encoded data over the wire
-    fn send_opus_packet(
-        &mut self,
-        socket: &UdpSocket,
-        addr: &std::net::SocketAddr,
-        size: usize,
-    ) -> Result<(), ProtocolError> {
-        self.sequence = self.sequence.wrapping_add(1);
-        let size = self.prepare_packet(size)?;
-        // println!("Sending buffer: {:?}", &self.buffer[0..size]);
-        match socket.send_to(&self.buffer[0..BUFFER_OFFSET+size], addr) {
-            Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
-                println!(
-                    "A packet has been dropped (seq: {}, timestamp: {})",
-                    &self.sequence, &self.timestamp
-                );
-                return Ok(());
-            }
-            Err(e) => return Err(ProtocolError::from(e)),
-            _ => {}
+/// How many frames in a row can fail to encode before the player gives up.
+/// A single failure only skips that frame.
+const MAX_CONSECUTIVE_ENCODE_ERRORS: u32 = 10;
+
+/// An Opus packet of 20ms of silence
+pub const SILENCE_FRAME: [u8; 3] = [0xF8, 0xFF, 0xFE];
+
+/// How many silence frames are sent at the start of a stretch of silence before the
+/// rest of it is suppressed, so the receiver's decoder fades out rather than cuts off
+const SILENCE_FRAMES_BEFORE_SUPPRESSING: u32 = 5;
+
+/// What to do with a PCM frame according to the silence threshold
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SilenceAction {
+    Encode,
+    SendSilence,
+    Suppress,
+}
+
+/// Keeps track of how long the PCM has been silent for
+struct SilenceDetector {
+    threshold: Option<u16>,
+    silent_frames: u32,
+}
+
+impl SilenceDetector {
+    fn new(threshold: Option<u16>) -> Self {
+        Self {
+            threshold,
+            silent_frames: 0,
+        }
+    }
+
+    fn check(&mut self, pcm: &[i16]) -> SilenceAction {
+        let threshold = match self.threshold {
+            Some(threshold) => threshold as i32,
+            None => return SilenceAction::Encode,
         };
 
-        self.timestamp = self.timestamp.wrapping_add(SAMPLES_PER_FRAME);
-        Ok(())
+        if pcm.iter().any(|&sample| (sample as i32).abs() > threshold) {
+            self.silent_frames = 0;
+            return SilenceAction::Encode;
+        }
+
+        self.silent_frames = self.silent_frames.saturating_add(1);
+        if self.silent_frames <= SILENCE_FRAMES_BEFORE_SUPPRESSING {
+            SilenceAction::SendSilence
+        } else {
+            SilenceAction::Suppress
+        }
     }
 }
 
-type Protocol = Arc<Mutex<DiscordVoiceProtocol>>;
-type Source = Arc<Mutex<Box<dyn AudioSource>>>;
+/// Why the player thread finished
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FinishReason {
+    /// The source ran out of audio
+    Finished,
+    /// AudioPlayer::stop was called
+    Stopped,
+    /// AudioPlayer::skip was called
+    Skipped,
+    /// An error occurred while playing
+    Error,
+}
+
+impl FinishReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FinishReason::Finished => "finished",
+            FinishReason::Stopped => "stopped",
+            FinishReason::Skipped => "skipped",
+            FinishReason::Error => "error",
+        }
+    }
+}
 
 #[allow(dead_code)]
 pub struct AudioPlayer {
-    thread: thread::JoinHandle<()>,
+    thread: Option<thread::JoinHandle<()>>,
+    // Disconnects once the thread is done, since a JoinHandle can't be waited on with a timeout
+    done: Receiver<()>,
+    protocol: Protocol,
+    state: Arc<PlayingState>,
+    source: Source,
+    bitrate: Bitrate,
+    options: EncoderOptions,
+    // The state is shared with the protocol so stopping has to be tracked separately
+    // from the source running out, since both end up setting the FINISHED state
+    stopped: Arc<AtomicBool>,
+    // The number of frames played so far, adjusted when seeking
+    frames: Arc<AtomicU64>,
+    queue: Queue,
+    skip: Arc<AtomicBool>,
+    looping: Arc<AtomicBool>,
+    raw_frames: RawFrames,
+    encoder_info: SharedEncoderInfo,
+    stats: Arc<PacketStats>,
+    performance: Arc<PerformanceStats>,
+}
+
+/// The state shared between the AudioPlayer and its thread
+struct PlayerShared {
     protocol: Protocol,
     state: Arc<PlayingState>,
     source: Source,
+    queue: Queue,
+    bitrate: Bitrate,
+    options: EncoderOptions,
+    frames: Arc<AtomicU64>,
+    skip: Arc<AtomicBool>,
+    looping: Arc<AtomicBool>,
+    // While set the source is still read but silence is sent in its place
+    muted: Arc<AtomicBool>,
+    pcm_tap: PcmTap,
+    raw_frames: RawFrames,
+    encoder_info: SharedEncoderInfo,
+    speaking: SpeakingFlags,
+    send_policy: SendPolicy,
+    packet_loss: PacketLoss,
+    stats: Arc<PacketStats>,
+    performance: Arc<PerformanceStats>,
 }
 
-fn audio_play_loop(
-    protocol: &Protocol,
-    state: &Arc<PlayingState>,
-    source: &Source,
-) -> Result<(), ProtocolError> {
-    let mut next_iteration = Instant::now();
+/// Plays the source followed by anything in the queue, calling `track_ended` as each
+/// track other than the last one ends. Returns why the last track ended.
+fn audio_play_loop<TrackEnded>(
+    shared: &PlayerShared,
+    track_ended: &mut TrackEnded,
+) -> Result<FinishReason, ProtocolError>
+where
+    TrackEnded: FnMut(FinishReason, Option<ProtocolError>),
+{
+    let PlayerShared {
+        protocol,
+        state,
+        source,
+        queue,
+        bitrate,
+        options,
+        frames,
+        skip,
+        looping,
+        muted,
+        pcm_tap,
+        raw_frames,
+        encoder_info,
+        speaking,
+        send_policy,
+        packet_loss,
+        stats,
+        performance,
+    } = shared;
+    let mut clock = FrameClock::new(options.frame_duration(), Instant::now());
+    let mut silence = SilenceDetector::new(options.silence_threshold);
+    let mut encode_errors = 0;
 
-    let (mut encoder, mut socket) = {
-        let mut proto = protocol.lock();
-        proto.speaking(SpeakingFlags::microphone())?;
-        (AudioEncoder::from_protocol(&*proto)?, proto.clone_socket()?)
+    let (mut encoder, mut socket, mut addr, mut key_generation) = {
+        let proto = protocol.lock();
+        proto.clone_sender().speaking(speaking.clone(), false)?;
+        let (encoder, socket, addr) =
+            connect_output(&proto, bitrate.load(Ordering::Relaxed), options)?;
+        (encoder, socket, addr, state.key_generation())
     };
 
-    let addr = socket.peer_addr()?;
     println!("Socket connected to: {:?}", &addr);
+    // Rebuilt encoders get the same settings, so this only changes with the bitrate
+    publish_encoder_info(&encoder, encoder_info);
+    state.start_playing();
 
     loop {
         if state.is_finished() {
@@ -350,35 +2058,140 @@ fn audio_play_loop(
         if state.is_paused() {
             // Wait until we're no longer paused
             state.wait_until_not_paused();
+            clock.reset(Instant::now());
             continue;
         }
 
         if state.is_disconnected() {
             // Wait until we're connected again to reset our state
-            state.wait_until_connected();
-            next_iteration = Instant::now();
+            state.wait_while_disconnected();
+            if state.is_finished() {
+                break;
+            }
+            clock.reset(Instant::now());
+
+            let proto = protocol.lock();
+            // A reconnect can move the session to another voice server, so the
+            // address is picked up again along with the new socket
+            let output = connect_output(&proto, bitrate.load(Ordering::Relaxed), options)?;
+            encoder = output.0;
+            socket = output.1;
+            addr = output.2;
+            key_generation = state.key_generation();
+            // The flags may have been sent before, but the new connection has to hear them
+            proto.clone_sender().speaking(speaking.clone(), true)?;
+            state.start_playing();
+        }
 
+        if state.key_generation() != key_generation {
+            // The key was rotated mid-session, only the cipher has to change
             let proto = protocol.lock();
-            encoder = AudioEncoder::from_protocol(&*proto)?;
-            socket = proto.clone_socket()?;
+            encoder.rekey(proto.encryption, &proto.secret_key);
+            key_generation = state.key_generation();
+        }
+
+        let target_bitrate = bitrate.load(Ordering::Relaxed);
+        if target_bitrate != encoder.bitrate {
+            encoder.set_bitrate(target_bitrate)?;
+            publish_encoder_info(&encoder, encoder_info);
+        }
+
+        // Raw frames take the place of the next frame from the source, so they get
+        // the next sequence and timestamp and are paced like any other frame
+        let raw_frame = raw_frames.lock().pop_front();
+        if let Some(frame) = raw_frame {
+            encoder.buffer[BUFFER_OFFSET..BUFFER_OFFSET + frame.len()].copy_from_slice(&frame);
+            if encoder.send_opus_packet(&socket, &addr, frame.len(), *send_policy, None)? {
+                stats.sent.fetch_add(1, Ordering::Relaxed);
+            } else {
+                stats.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            thread::sleep(clock.tick(Instant::now()));
+            continue;
         }
 
-        next_iteration += Duration::from_millis(20);
-        let buffer_size = {
+        let skipped = skip.swap(false, Ordering::Relaxed);
+        let mut suppressed = false;
+        // Opus sources and silence aren't encoded so they take no time
+        let mut encode_time = Duration::from_millis(0);
+        let buffer_size = if skipped {
+            None
+        } else {
             let mut aud = source.lock();
             match aud.get_type() {
-                AudioType::Opus => aud.read_opus_frame(&mut encoder.buffer[BUFFER_OFFSET..]),
+                AudioType::Opus => {
+                    let size = aud.read_opus_frame(&mut encoder.buffer[BUFFER_OFFSET..]);
+                    // Opus packets carry their own channel count in the stereo bit of the TOC byte
+                    let stereo = encoder.buffer[BUFFER_OFFSET] & 0x04 != 0;
+                    if size.unwrap_or(0) > 0 && stereo && encoder.channels == 1 {
+                        return Err(custom_error(
+                            "opus source is stereo but the encoder is configured for mono",
+                        ));
+                    }
+                    // The packets are sent as they are so they're paced as 20ms frames
+                    if options.frame_length != FRAME_LENGTH {
+                        return Err(custom_error(
+                            "opus sources can only be played with 20ms frames",
+                        ));
+                    }
+                    match size {
+                        Some(size) if size > 0 && muted.load(Ordering::Relaxed) => {
+                            let end = BUFFER_OFFSET + SILENCE_FRAME.len();
+                            encoder.buffer[BUFFER_OFFSET..end].copy_from_slice(&SILENCE_FRAME);
+                            Some(SILENCE_FRAME.len())
+                        }
+                        size => size,
+                    }
+                }
                 AudioType::Pcm => {
-                    if let Some(_) = aud.read_pcm_frame(&mut encoder.pcm_buffer) {
+                    let samples = encoder.pcm_samples;
+                    if let Some(_) = aud.read_pcm_frame(&mut encoder.pcm_buffer[..samples]) {
                         // println!("Read {} bytes", &num);
-                        match encoder.encode_pcm_buffer() {
-                            Ok(bytes) => {
-                                // println!("Encoded {} bytes", &bytes);
-                                Some(bytes)
+                        let action = if muted.load(Ordering::Relaxed) {
+                            // Frames longer than 20ms have no silence frame so the
+                            // silence is encoded instead
+                            for sample in encoder.pcm_buffer[..samples].iter_mut() {
+                                *sample = 0;
+                            }
+                            SilenceAction::SendSilence
+                        } else {
+                            silence.check(&encoder.pcm_buffer[..samples])
+                        };
+                        tap_pcm(pcm_tap, &encoder.pcm_buffer[..samples]);
+                        match action {
+                            // The silence frame is a 20ms frame
+                            SilenceAction::SendSilence
+                                if encoder.samples_per_frame == SAMPLES_PER_FRAME =>
+                            {
+                                let end = BUFFER_OFFSET + SILENCE_FRAME.len();
+                                encoder.buffer[BUFFER_OFFSET..end].copy_from_slice(&SILENCE_FRAME);
+                                Some(SILENCE_FRAME.len())
                             }
-                            Err(e) => {
-                                println!("Error encoding bytes: {:?}", &e);
-                                return Err(e.into());
+                            SilenceAction::Encode | SilenceAction::SendSilence => {
+                                let started = Instant::now();
+                                match encoder.encode_pcm_buffer() {
+                                    Ok(bytes) => {
+                                        // println!("Encoded {} bytes", &bytes);
+                                        encode_time = started.elapsed();
+                                        encode_errors = 0;
+                                        Some(bytes)
+                                    }
+                                    Err(e) => {
+                                        println!("Error encoding bytes: {:?}", &e);
+                                        performance.encode_errors.fetch_add(1, Ordering::Relaxed);
+                                        encode_errors += 1;
+                                        if encode_errors > MAX_CONSECUTIVE_ENCODE_ERRORS {
+                                            return Err(e.into());
+                                        }
+                                        // The frame is skipped the same way as a suppressed one
+                                        suppressed = true;
+                                        Some(0)
+                                    }
+                                }
+                            }
+                            SilenceAction::Suppress => {
+                                suppressed = true;
+                                Some(0)
                             }
                         }
                     } else {
@@ -389,49 +2202,228 @@ fn audio_play_loop(
         };
 
         if let Some(size) = buffer_size {
-            if size != 0 {
-                encoder.send_opus_packet(&socket, &addr, size)?;
-                let now = Instant::now();
-                next_iteration = next_iteration.max(now);
-                thread::sleep(next_iteration - now);
+            if suppressed {
+                // Nothing is sent but the frame still takes up its time
+                encoder.skip_frame();
+                frames.fetch_add(1, Ordering::Relaxed);
+                thread::sleep(clock.tick(Instant::now()));
+            } else if size != 0 {
+                let mut simulation = packet_loss.lock();
+                let sent = encoder.send_opus_packet(
+                    &socket,
+                    &addr,
+                    size,
+                    *send_policy,
+                    simulation.as_mut(),
+                )?;
+                drop(simulation);
+                if sent {
+                    stats.sent.fetch_add(1, Ordering::Relaxed);
+                } else {
+                    stats.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                frames.fetch_add(1, Ordering::Relaxed);
+
+                let wait = clock.tick(Instant::now());
+                performance.frames.fetch_add(1, Ordering::Relaxed);
+                PerformanceStats::add(&performance.encode_nanos, encode_time);
+                PerformanceStats::add(&performance.encrypt_nanos, encoder.encrypt_time);
+                PerformanceStats::add(&performance.sleep_nanos, wait);
+                if wait == Duration::from_millis(0) {
+                    performance.late_frames.fetch_add(1, Ordering::Relaxed);
+                }
+                thread::sleep(wait);
+            }
+            continue;
+        }
+
+        // The current track is over, move on to the next one if there is one.
+        // The encoder, socket and speaking state are kept as they are.
+        let mut error = if skipped {
+            None
+        } else {
+            source.lock().take_error()
+        };
+
+        // A looping track starts over instead, unless it was skipped or failed
+        if !skipped && error.is_none() && looping.load(Ordering::Relaxed) {
+            let mut aud = source.lock();
+            if aud.can_restart() {
+                match aud.restart() {
+                    Ok(()) => {
+                        frames.store(0, Ordering::Relaxed);
+                        continue;
+                    }
+                    // The track ends with the error instead, which is passed on to after
+                    Err(e) => error = Some(e),
+                }
             }
+        }
+
+        let reason = if skipped {
+            FinishReason::Skipped
+        } else if error.is_some() {
+            FinishReason::Error
         } else {
-            state.finished();
+            FinishReason::Finished
+        };
+
+        let mut pending = queue.lock();
+        match pending.pop_front() {
+            Some(next) => {
+                *source.lock() = next;
+                frames.store(0, Ordering::Relaxed);
+                track_ended(reason, error);
+            }
+            None => {
+                // The queue lock is held so nothing can be enqueued after finishing
+                state.finished();
+                return match error {
+                    Some(error) => Err(error),
+                    None => Ok(reason),
+                };
+            }
         }
     }
 
-    Ok(())
+    Ok(FinishReason::Finished)
 }
 
 impl AudioPlayer {
-    pub fn new<After>(after: After, protocol: Protocol, source: Source) -> Self
+    #[allow(clippy::too_many_arguments)]
+    pub fn new<After>(
+        mut after: After,
+        protocol: Protocol,
+        source: Source,
+        bitrate: Bitrate,
+        options: EncoderOptions,
+        speaking: SpeakingFlags,
+        send_policy: SendPolicy,
+        packet_loss: PacketLoss,
+        muted: Arc<AtomicBool>,
+        pcm_tap: PcmTap,
+    ) -> Self
     where
-        After: FnOnce(Option<ProtocolError>) -> (),
+        After: FnMut(usize, FinishReason, Option<ProtocolError>) -> (),
         After: Send + 'static,
     {
-        let state = {
+        let (state, sender) = {
             let guard = protocol.lock();
-            guard.clone_state()
+            (guard.clone_state(), guard.clone_sender())
         };
         state.connected();
-
-        Self {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let (done_sender, done) = bounded::<()>(0);
+        let shared = PlayerShared {
             protocol: Arc::clone(&protocol),
             state: Arc::clone(&state),
             source: Arc::clone(&source),
-            thread: thread::spawn(move || {
-                let mut current_error = None;
-                if let Err(e) = audio_play_loop(&protocol, &state, &source) {
-                    current_error = Some(e);
-                }
-                {
-                    let mut proto = protocol.lock();
-                    // ignore the error
-                    let _ = proto.speaking(SpeakingFlags::off());
-                }
-                after(current_error);
-            }),
+            queue: Arc::new(Mutex::new(VecDeque::new())),
+            bitrate: Arc::clone(&bitrate),
+            options,
+            frames: Arc::new(AtomicU64::new(0)),
+            skip: Arc::new(AtomicBool::new(false)),
+            looping: Arc::new(AtomicBool::new(false)),
+            muted,
+            pcm_tap,
+            raw_frames: Arc::new(Mutex::new(VecDeque::new())),
+            encoder_info: Arc::new(Mutex::new(None)),
+            speaking,
+            send_policy,
+            packet_loss,
+            stats: Arc::new(PacketStats::default()),
+            performance: Arc::new(PerformanceStats::default()),
+        };
+
+        Self {
+            protocol,
+            state,
+            source,
+            bitrate,
+            options,
+            stopped: Arc::clone(&stopped),
+            frames: Arc::clone(&shared.frames),
+            queue: Arc::clone(&shared.queue),
+            skip: Arc::clone(&shared.skip),
+            looping: Arc::clone(&shared.looping),
+            raw_frames: Arc::clone(&shared.raw_frames),
+            encoder_info: Arc::clone(&shared.encoder_info),
+            stats: Arc::clone(&shared.stats),
+            performance: Arc::clone(&shared.performance),
+            done,
+            thread: Some(thread::spawn(move || {
+                let _done: Sender<()> = done_sender;
+                // The index of the current track, starting with the initial source
+                let mut track = 0;
+                let mut track_ended = |reason, error| {
+                    after(track, reason, error);
+                    track += 1;
+                };
+
+                let result = audio_play_loop(&shared, &mut track_ended);
+                // ignore the error
+                let _ = sender.speaking(SpeakingFlags::off(), false);
+
+                let (reason, error) = match result {
+                    Err(e) => (FinishReason::Error, Some(e)),
+                    Ok(_) if stopped.load(Ordering::Relaxed) => (FinishReason::Stopped, None),
+                    Ok(reason) => (reason, None),
+                };
+                track_ended(reason, error);
+            })),
+        }
+    }
+
+    /// Adds a source to play once the current one (and anything queued before it) ends.
+    /// The source is handed back if the player has already finished.
+    pub fn enqueue(&self, source: Box<dyn AudioSource>) -> Option<Box<dyn AudioSource>> {
+        let mut queue = self.queue.lock();
+        if self.state.is_finished() {
+            return Some(source);
+        }
+        queue.push_back(source);
+        None
+    }
+
+    /// Queues an already encoded Opus frame to be sent in place of the next frame
+    /// of the source, using the player's encoder so the RTP sequence carries on.
+    /// Frames queued while paused are sent once playback resumes.
+    /// The frame is handed back if the player has already finished.
+    pub fn send_raw_opus(&self, frame: Vec<u8>) -> Result<(), Vec<u8>> {
+        // The player thread decides to finish with the queue locked
+        let _queue = self.queue.lock();
+        if self.state.is_finished() {
+            return Err(frame);
+        }
+        self.raw_frames.lock().push_back(frame);
+        Ok(())
+    }
+
+    /// Ends the current track and moves on to the next queued one
+    pub fn skip(&self) {
+        self.skip.store(true, Ordering::Relaxed);
+    }
+
+    /// Sets whether the current track starts over when it ends instead of finishing.
+    /// Looping carries on across restarts until it's disabled, the track is skipped
+    /// or the player is stopped. Fails if the current source can't be restarted.
+    pub fn set_loop(&self, enabled: bool) -> Result<(), ProtocolError> {
+        if enabled && !self.source.lock().can_restart() {
+            return Err(custom_error(
+                "the current source can't be restarted, so it can't be looped",
+            ));
         }
+        self.looping.store(enabled, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn is_looping(&self) -> bool {
+        self.looping.load(Ordering::Relaxed)
+    }
+
+    /// The number of sources waiting to be played
+    pub fn queue_len(&self) -> usize {
+        self.queue.lock().len()
     }
 
     pub fn pause(&self) {
@@ -443,14 +2435,329 @@ impl AudioPlayer {
     }
 
     pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
         self.state.finished()
     }
 
+    /// Stops playback and waits up to the timeout for the player thread to exit.
+    /// Once it has, the sources are dropped, which kills any FFmpeg processes.
+    /// Returns false if the thread didn't exit in time, in which case it's left
+    /// to finish on its own.
+    pub fn shutdown(&mut self, timeout: Duration) -> bool {
+        self.stop();
+        self.queue.lock().clear();
+        match self.done.recv_timeout(timeout) {
+            Err(RecvTimeoutError::Disconnected) => {}
+            _ => return false,
+        }
+
+        // A panic has already been reported by the panic hook
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        *self.source.lock() = Box::new(BytesPCMAudio::new(Vec::new()));
+        true
+    }
+
     pub fn is_paused(&self) -> bool {
         self.state.is_paused()
     }
 
+    /// Sets the bitrate of the encoder, which is applied on the next frame
+    pub fn set_bitrate(&self, bitrate: i32) {
+        self.bitrate.store(bitrate, Ordering::Relaxed);
+    }
+
+    pub fn is_seekable(&self) -> bool {
+        self.source.lock().is_seekable()
+    }
+
+    pub fn can_restart(&self) -> bool {
+        self.source.lock().can_restart()
+    }
+
+    /// The kind of the current source, whether it can seek and its audio type
+    pub fn source_info(&self) -> (&'static str, bool, AudioType) {
+        let source = self.source.lock();
+        (source.kind(), source.is_seekable(), source.get_type())
+    }
+
+    /// Seeks the current source to the given position in seconds.
+    /// The encoder is left untouched so the RTP sequence and timestamp stay
+    /// continuous, however the audio itself jumps so the receiver's inband FEC
+    /// state is effectively reset and the first frame after the seek can't be
+    /// recovered from the previous packet.
+    /// Returns false without seeking if the current source isn't seekable. The
+    /// check is made under the same lock as the seek, so the player can't move on
+    /// to a queued source in between.
+    pub fn seek(&self, seconds: f64) -> Result<bool, ProtocolError> {
+        let mut source = self.source.lock();
+        if !source.is_seekable() {
+            return Ok(false);
+        }
+        source.seek(seconds)?;
+        let frames = seconds * 1000.0 / self.options.frame_length as f64;
+        self.frames.store(frames as u64, Ordering::Relaxed);
+        Ok(true)
+    }
+
+    /// Mixes a PCM source into the one playing without interrupting it, at the given gain.
+    /// The first time this moves the current source into a Mixer at full gain, which
+    /// means it can no longer be seeked.
+    pub fn mix_in(&self, source: Box<dyn AudioSource>, gain: f32) -> Result<(), ProtocolError> {
+        let mut current = self.source.lock();
+        if let Some(mixer) = current.as_mixer() {
+            return mixer.add(source, gain);
+        }
+        if let AudioType::Opus = current.get_type() {
+            return Err(custom_error("opus sources can't be mixed"));
+        }
+
+        let mut mixer = Mixer::new();
+        let playing = std::mem::replace(&mut *current, Box::new(BytesPCMAudio::new(Vec::new())));
+        mixer.add(playing, 1.0)?;
+        mixer.add(source, gain)?;
+        *current = Box::new(mixer);
+        Ok(())
+    }
+
+    /// Replaces the current source straight away without stopping the player thread,
+    /// so speaking isn't sent again and the encoder's sequence and timestamp carry on,
+    /// which makes the stream look continuous to Discord. A pending skip is cancelled.
+    /// The source is handed back if the player has already finished.
+    pub fn swap_source(&self, source: Box<dyn AudioSource>) -> Result<(), Box<dyn AudioSource>> {
+        // The player thread decides to finish with the queue locked
+        let _queue = self.queue.lock();
+        if self.state.is_finished() {
+            return Err(source);
+        }
+
+        *self.source.lock() = source;
+        self.skip.store(false, Ordering::Relaxed);
+        self.frames.store(0, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Replaces the current source without stopping the player thread, fading
+    /// between them over the given number of frames. The speaking state is kept.
+    /// Opus sources can't be mixed so they're swapped without a fade.
+    pub fn crossfade_to(&self, source: Box<dyn AudioSource>, frames: u32) {
+        let mut current = self.source.lock();
+        let can_fade = matches!(current.get_type(), AudioType::Pcm)
+            && matches!(source.get_type(), AudioType::Pcm);
+        if can_fade {
+            let from = std::mem::replace(&mut *current, Box::new(BytesPCMAudio::new(Vec::new())));
+            *current = Box::new(Crossfade::new(from, source, frames));
+        } else {
+            *current = source;
+        }
+        self.frames.store(0, Ordering::Relaxed);
+    }
+
+    /// Whether the player thread has stopped or is about to
+    pub fn is_finished(&self) -> bool {
+        self.state.is_finished()
+    }
+
+    /// The number of packets sent and dropped so far
+    pub fn packet_counts(&self) -> (u64, u64) {
+        (
+            self.stats.sent.load(Ordering::Relaxed),
+            self.stats.dropped.load(Ordering::Relaxed),
+        )
+    }
+
+    /// The settings read back from the player's encoder, None until it has been created
+    pub fn encoder_info(&self) -> Option<EncoderInfo> {
+        *self.encoder_info.lock()
+    }
+
+    /// Where the player thread has spent its time so far
+    pub fn performance(&self) -> &PerformanceStats {
+        &self.performance
+    }
+
+    /// How far into the source playback is, in seconds
+    pub fn position(&self) -> f64 {
+        let frames = self.frames.load(Ordering::Relaxed);
+        frames as f64 * self.options.frame_length as f64 / 1000.0
+    }
+
+    /// The length of each frame in milliseconds
+    pub fn frame_length(&self) -> u16 {
+        self.options.frame_length
+    }
+
     pub fn is_playing(&self) -> bool {
         self.state.is_playing()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::tests as protocol_tests;
+    use crate::receiver::{decrypter_for, MAX_DECODED_SAMPLES};
+    use crate::transport::MockTransport;
+
+    const FRAME: Duration = Duration::from_millis(20);
+
+    fn rms(samples: &[i16]) -> f64 {
+        let sum: f64 = samples.iter().map(|&s| f64::from(s) * f64::from(s)).sum();
+        (sum / samples.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn frame_clock_does_not_drift() {
+        let epoch = Instant::now();
+        let mut clock = FrameClock::new(FRAME, epoch);
+        let mut now = epoch;
+        for n in 1..=1000u32 {
+            // Encoding and sending takes a varying amount of time
+            now += Duration::from_micros(u64::from(n % 7) * 500);
+            let wait = clock.tick(now);
+            assert_eq!(clock.deadline(), epoch + FRAME * n);
+            assert_eq!(now + wait, clock.deadline());
+            now += wait;
+        }
+        assert_eq!(now, epoch + FRAME * 1000);
+    }
+
+    #[test]
+    fn frame_clock_catches_up_without_drifting() {
+        let epoch = Instant::now();
+        let mut clock = FrameClock::new(FRAME, epoch);
+        let mut now = epoch;
+        let mut skipped = 0;
+        for n in 1..=1000u32 {
+            // Every tenth frame takes longer than a frame, which puts the clock behind
+            let work = if n % 10 == 0 { 50 } else { 1 };
+            now += Duration::from_millis(work);
+            let wait = clock.tick(now);
+            assert_eq!(clock.deadline(), epoch + FRAME * n);
+            if now >= clock.deadline() {
+                assert_eq!(wait, Duration::from_millis(0));
+                skipped += 1;
+            } else {
+                assert_eq!(now + wait, clock.deadline());
+            }
+            now += wait;
+        }
+        assert!(skipped > 0);
+        // Frames that were late are made up for by the ones after them
+        assert_eq!(now, epoch + FRAME * 1000 + Duration::from_millis(30));
+    }
+
+    #[test]
+    fn encoded_packets_decrypt_and_decode() {
+        let key = [7u8; 32];
+        let ssrc = 1234u32;
+        // The plain mode isn't covered since its packets carry the nonce after the
+        // data, which the receiver doesn't expect as Discord doesn't send it
+        let modes = [
+            EncryptionMode::XSalsa20Poly1305Suffix,
+            EncryptionMode::XSalsa20Poly1305Lite,
+            EncryptionMode::AeadXChaCha20Poly1305RtpSize,
+        ];
+        for &mode in &modes {
+            let options = EncoderOptions::default();
+            let mut encoder =
+                AudioEncoder::new(mode, &key, ssrc, DEFAULT_BITRATE, &options).unwrap();
+            // A 440Hz tone in both channels
+            let mut input = vec![0i16; options.frame_samples()];
+            for (i, frame) in input.chunks_mut(2).enumerate() {
+                let t = i as f64 / f64::from(SAMPLING_RATE);
+                let sample = ((t * 440.0 * 2.0 * std::f64::consts::PI).sin() * 8000.0) as i16;
+                frame[0] = sample;
+                frame[1] = sample;
+            }
+
+            let cipher = VoiceCipher::new(mode, &key);
+            let decrypt = decrypter_for(mode);
+            let mut decoder = audiopus::coder::Decoder::new(
+                audiopus::SampleRate::Hz48000,
+                audiopus::Channels::Stereo,
+            )
+            .unwrap();
+            let mut decrypted = [0u8; MAX_BUFFER_SIZE];
+            let mut output = [0i16; MAX_DECODED_SAMPLES];
+            let mut samples = 0;
+
+            // Opus takes a few frames to settle, so only the last one is compared
+            for _ in 0..10 {
+                encoder.pcm_buffer_mut().copy_from_slice(&input);
+                let size = encoder.encode_pcm_buffer().unwrap();
+                let opus = encoder.buffer[BUFFER_OFFSET..BUFFER_OFFSET + size].to_vec();
+                let length = BUFFER_OFFSET + encoder.prepare_packet(size).unwrap();
+                let packet = &encoder.buffer[..length];
+                assert_eq!(packet[8..12], ssrc.to_be_bytes());
+
+                let size = decrypt(&cipher, packet, &mut decrypted).unwrap();
+                assert_eq!(&decrypted[..size], &opus[..]);
+                samples = decoder
+                    .decode(Some(&decrypted[..size]), &mut output[..], false)
+                    .unwrap();
+            }
+
+            assert_eq!(samples, options.samples_per_frame() as usize);
+            let decoded = &output[..options.frame_samples()];
+            let (expected, actual) = (rms(&input), rms(decoded));
+            assert!(
+                (actual - expected).abs() < expected * 0.25,
+                "{} RMS after decoding, {} before",
+                actual,
+                expected
+            );
+        }
+    }
+
+    /// Reads the next RTP packet sent to a voice server, skipping UDP keepalives
+    fn next_voice_packet(server: &UdpSocket) -> Option<Vec<u8>> {
+        let mut buffer = [0u8; MAX_BUFFER_SIZE];
+        while let Ok(size) = server.recv(&mut buffer) {
+            if size > 12 && buffer[0] == 0x80 && buffer[1] == 0x78 {
+                return Some(buffer[..size].to_vec());
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn packets_follow_the_voice_server_after_reconnecting() {
+        let mock = MockTransport::new();
+        let mut proto = protocol_tests::protocol(&mock);
+        mock.push_text(protocol_tests::HELLO);
+        assert!(proto.poll_once().unwrap());
+
+        let options = EncoderOptions::default();
+        let mut previous: Option<UdpSocket> = None;
+        for &ssrc in &[1234u32, 5678] {
+            // Each READY is for a different voice server, as after a reconnect
+            let (port, server) = protocol_tests::discovery_responder("203.0.113.5", 50000);
+            mock.push_text(&format!(
+                r#"{{"op": 2, "d": {{"ssrc": {}, "ip": "127.0.0.1", "port": {}, "modes": ["aead_xchacha20_poly1305_rtpsize"], "heartbeat_interval": 1}}}}"#,
+                ssrc, port
+            ));
+            assert!(proto.poll_once().unwrap());
+            let server = server.join().unwrap();
+            server
+                .set_read_timeout(Some(Duration::from_millis(200)))
+                .unwrap();
+
+            let (mut encoder, socket, addr) =
+                connect_output(&proto, DEFAULT_BITRATE, &options).unwrap();
+            assert_eq!(addr, server.local_addr().unwrap());
+            encoder.buffer[BUFFER_OFFSET..BUFFER_OFFSET + 3].copy_from_slice(&[0xf8, 0xff, 0xfe]);
+            assert!(encoder
+                .send_opus_packet(&socket, &addr, 3, SendPolicy::Block, None)
+                .unwrap());
+
+            let packet = next_voice_packet(&server).expect("the current server got no packet");
+            assert_eq!(packet[8..12], ssrc.to_be_bytes());
+            if let Some(previous) = &previous {
+                assert!(next_voice_packet(previous).is_none());
+            }
+            previous = Some(server);
+        }
+    }
+}
@@ -1,21 +1,216 @@
+//! The voice websocket protocol.
+//!
+//! Threading model: the `DiscordVoiceProtocol` is shared behind a mutex, but only the
+//! thread running `VoiceConnection.run` reads from and writes to the websocket, through
+//! `poll`. The socket read times out (after `POLL_TIMEOUT` by default), so the lock is
+//! only held briefly per iteration and is released fairly so other threads can get it
+//! in between.
+//! Other threads (e.g. the audio player) never write to the websocket directly. Instead
+//! they queue payloads through a `MessageSender`, which doesn't require the lock, and
+//! `poll` writes them out before reading the next message.
+
 #![allow(dead_code)]
 
 use tungstenite::error::Error as TungError;
-use tungstenite::protocol::{frame::coding::CloseCode, frame::CloseFrame, WebSocket};
+use tungstenite::protocol::{
+    frame::coding::CloseCode, frame::CloseFrame, WebSocket, WebSocketConfig,
+};
 use tungstenite::Message;
 
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream, UdpSocket};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
 use std::str::FromStr;
 use std::sync::Arc;
-use std::time::Instant;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use std::io::ErrorKind;
 
-use native_tls::{TlsConnector, TlsStream};
+use crossbeam_channel::{bounded, unbounded, Receiver, RecvTimeoutError, Sender};
+use native_tls::{Certificate, TlsConnector, TlsStream};
+use parking_lot::Mutex;
+use serde::Serialize;
 
 use crate::error::*;
 use crate::payloads::*;
 use crate::state::PlayingState;
+use crate::transport::Transport;
+
+/// The default of how long a single poll waits for a websocket message, and
+/// therefore the longest a queued payload waits before it is written.
+/// This is independent of the heartbeat interval, it only has to be short
+/// enough for poll to heartbeat on time and stay responsive.
+pub const POLL_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// The default limit on connecting to each of the endpoint's addresses, which also
+/// applies to the TLS and websocket handshakes
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often a keepalive is sent over UDP while no audio is playing
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long to wait for RESUMED after sending RESUME before identifying instead
+pub const RESUME_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many times UDP discovery is attempted before giving up
+pub const DISCOVERY_ATTEMPTS: u32 = 5;
+
+/// How long each UDP discovery attempt waits for a response
+pub const DISCOVERY_RECV_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The delay before the first UDP discovery retry, doubled for every retry after it
+pub const DISCOVERY_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// The size of a UDP discovery packet, both the request and the response
+const DISCOVERY_PACKET_SIZE: usize = 74;
+/// The packet types of a UDP discovery request and response
+const DISCOVERY_REQUEST: u16 = 1;
+const DISCOVERY_RESPONSE: u16 = 2;
+/// The length field of a UDP discovery packet, which excludes the type and the length
+const DISCOVERY_LENGTH: u16 = 70;
+
+/// The default write timeout of the voice socket. Sends that can't complete within
+/// it fail with WouldBlock or TimedOut and are handled by the player's send policy,
+/// rather than blocking the player and delaying the frames after them.
+pub const DEFAULT_SEND_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// The default limit on the size of a websocket message. Voice gateway payloads are
+/// a few kilobytes at most, so anything near this is a broken or hostile server.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 1 << 20;
+
+/// Whether a session closed with the given code can be resumed on a new websocket.
+/// The codes below mean the session itself is gone, so reconnecting has to identify
+/// a new one instead:
+///   4006 - the session is no longer valid
+///   4009 - the session timed out, also used when heartbeats stop being acknowledged
+///   4011 - the voice server for the session wasn't found
+///   4012 - the protocol selected for the session isn't known
+///   4016 - the encryption mode selected for the session isn't known
+/// Anything else, such as the websocket dropping, is resumed. Codes that aren't
+/// reconnected after at all (1000, 4014 and 4015) never get this far.
+pub fn close_code_resumable(code: u16) -> bool {
+    !matches!(code, 4006 | 4009 | 4011 | 4012 | 4016)
+}
+
+/// Parses the response to UDP discovery into our external IP and port.
+/// The response is checked to be one before trusting it, since anything can
+/// send a packet to the socket: the type has to be a response, the length has
+/// to be right and the SSRC has to be ours.
+fn parse_discovery_response(buffer: &[u8], ssrc: u32) -> Result<(String, u16), ProtocolError> {
+    if buffer.len() != DISCOVERY_PACKET_SIZE {
+        return Err(custom_error(&format!(
+            "UDP discovery response is {} bytes rather than {}",
+            buffer.len(),
+            DISCOVERY_PACKET_SIZE
+        )));
+    }
+
+    let kind = u16::from_be_bytes([buffer[0], buffer[1]]);
+    if kind != DISCOVERY_RESPONSE {
+        return Err(custom_error(&format!(
+            "UDP discovery response has type {} rather than {}",
+            kind, DISCOVERY_RESPONSE
+        )));
+    }
+
+    let length = u16::from_be_bytes([buffer[2], buffer[3]]);
+    if length != DISCOVERY_LENGTH {
+        return Err(custom_error(&format!(
+            "UDP discovery response has length {} rather than {}",
+            length, DISCOVERY_LENGTH
+        )));
+    }
+
+    let echoed = u32::from_be_bytes([buffer[4], buffer[5], buffer[6], buffer[7]]);
+    if echoed != ssrc {
+        return Err(custom_error(&format!(
+            "UDP discovery response is for SSRC {} rather than ours ({})",
+            echoed, ssrc
+        )));
+    }
+
+    // The IP follows the SSRC and ends on the first encounter of a null byte
+    let address = &buffer[8..DISCOVERY_PACKET_SIZE - 2];
+    let ip_end = address
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or_else(|| custom_error("could not find end of IP"))?;
+    let ip = std::str::from_utf8(&address[..ip_end])
+        .map_err(|_| custom_error("invalid IP found (not UTF-8)"))?;
+    if ip.parse::<IpAddr>().is_err() {
+        return Err(custom_error("invalid IP found in UDP discovery response"));
+    }
+
+    // The port is the last 2 bytes in big endian
+    let port = u16::from_be_bytes([buffer[72], buffer[73]]);
+    Ok((String::from(ip), port))
+}
+
+/// Queues payloads to be sent over the websocket by the polling thread.
+/// This can be used from any thread without holding the protocol lock.
+#[derive(Clone)]
+pub struct MessageSender {
+    sender: Sender<Message>,
+    last_speaking: Arc<Mutex<Option<SpeakingFlags>>>,
+}
+
+impl MessageSender {
+    pub fn send<T: Serialize>(&self, payload: &T) -> Result<(), ProtocolError> {
+        let text = serde_json::to_string(payload)?;
+        self.sender
+            .send(Message::text(text))
+            .map_err(|_| custom_error("the protocol has been dropped"))
+    }
+
+    /// Sends the speaking flags unless they're the ones that were last sent, since
+    /// Discord rate limits SPEAKING. Force sends them regardless, e.g. to announce
+    /// them again after reconnecting.
+    pub fn speaking(&self, flags: SpeakingFlags, force: bool) -> Result<(), ProtocolError> {
+        self.speaking_with_delay(flags, 0, force)
+    }
+
+    pub fn speaking_with_delay(
+        &self,
+        flags: SpeakingFlags,
+        delay: u32,
+        force: bool,
+    ) -> Result<(), ProtocolError> {
+        // Held while sending so concurrent senders can't both skip or both send
+        let mut last = self.last_speaking.lock();
+        if !force && last.as_ref() == Some(&flags) {
+            return Ok(());
+        }
+        self.send(&Speaking::new(flags.clone(), delay))?;
+        *last = Some(flags);
+        Ok(())
+    }
+}
+
+/// Periodically sends a small packet over the voice socket while no audio is
+/// playing so the NAT mapping to the voice server doesn't expire.
+/// The background thread stops when this is dropped.
+pub struct UdpKeepalive {
+    _stop: Sender<()>,
+}
+
+impl UdpKeepalive {
+    pub fn new(socket: UdpSocket, ssrc: u32, state: Arc<PlayingState>) -> Self {
+        let (stop, stopped) = bounded::<()>(0);
+        thread::spawn(move || {
+            let mut packet = [0u8; 8];
+            packet[0..4].copy_from_slice(&ssrc.to_be_bytes());
+            // Nothing is ever sent, so this only stops timing out once the sender is dropped
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(KEEPALIVE_INTERVAL) {
+                if state.is_playing() {
+                    continue;
+                }
+                // A keepalive that can't be sent is tried again next time
+                let _ = socket.send(&packet);
+            }
+        });
+        Self { _stop: stop }
+    }
+}
 
 pub struct DiscordVoiceProtocol {
     pub endpoint: String,
@@ -25,16 +220,122 @@ pub struct DiscordVoiceProtocol {
     pub session_id: String,
     pub token: String,
     pub recent_acks: std::collections::VecDeque<f64>,
-    ws: WebSocket<TlsStream<TcpStream>>,
+    ws: Box<dyn Transport>,
+    outgoing: Sender<Message>,
+    pending: Receiver<Message>,
+    /// The speaking flags last sent, shared with every MessageSender
+    last_speaking: Arc<Mutex<Option<SpeakingFlags>>>,
     close_code: u16,
     state: Arc<PlayingState>,
+    ssrc_map: Arc<Mutex<HashMap<u32, u64>>>,
+    /// SSRCs of users that have left, for the audio receiver to drop their decoders
+    departed_ssrcs: Arc<Mutex<Vec<u32>>>,
+    /// Events since the last call to take_events
+    events: Vec<ProtocolEvent>,
+    pub speaking_states: HashMap<u64, u8>,
     socket: Option<UdpSocket>,
+    /// The write timeout of the voice socket, kept across new sockets
+    send_timeout: Duration,
+    keepalive: Option<UdpKeepalive>,
+    /// Our external address as found by UDP discovery for the current socket
+    discovered: Option<(String, u16)>,
     pub port: u16,
     heartbeat_interval: u64,
     pub last_heartbeat: Instant,
+    pub last_heartbeat_ack: Instant,
+    heartbeat_nonce: u64,
+    pub ssrc: u32,
+    pub encryption: EncryptionMode,
+    /// Every encryption mode offered by the voice server in READY
+    pub available_modes: Vec<String>,
+    pub secret_key: [u8; 32],
+    pub reconnect_policy: ReconnectPolicy,
+    /// Kept so reconnects verify the new websocket the same way
+    tls: TlsOptions,
+    /// Used instead of the best encryption mode if READY offers it
+    pub preferred_encryption: Option<EncryptionMode>,
+    /// Messages larger than this are refused rather than parsed
+    max_message_size: usize,
+    /// The address family tried first when connecting the websocket
+    preferred_family: Option<IpFamily>,
+    connect_timeout: Duration,
+    /// How long a poll waits for a websocket message
+    read_timeout: Duration,
+    pub reconnect_attempts: u32,
+    /// When RESUME was sent, if RESUMED hasn't been received yet
+    resume_sent: Option<Instant>,
+    /// Whether the discovered address has to be selected once the session is resumed,
+    /// because a restored session's socket was discovered somewhere else
+    select_after_resume: bool,
+    /// Whether the last reconnect resumed the session rather than starting a new one
+    pub was_resumed: bool,
+    /// When the current websocket was opened
+    opened_at: Instant,
+    /// When the handshake on the current websocket finished, with either
+    /// the session description or RESUMED
+    connected_at: Option<Instant>,
+}
+
+/// Controls how resumable disconnects are retried.
+/// The delay before each attempt is `min(base_delay * multiplier^attempt, max_delay)`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReconnectPolicy {
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub multiplier: f64,
+    pub max_attempts: u32,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            multiplier: 2.0,
+            max_attempts: 5,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let delay = self.base_delay.as_secs_f64() * self.multiplier.powi(attempt as i32);
+        Duration::from_secs_f64(delay.min(self.max_delay.as_secs_f64()))
+    }
+}
+
+/// What's needed to pick a voice session back up on a new websocket, e.g. after the
+/// process restarts, as exported by VoiceConnection.get_state
+#[derive(Debug, Clone)]
+pub struct SavedSession {
+    pub endpoint_ip: String,
+    pub port: u16,
     pub ssrc: u32,
     pub encryption: EncryptionMode,
     pub secret_key: [u8; 32],
+    /// The address UDP discovery found for the old socket, if it was known
+    pub discovered: Option<(String, u16)>,
+}
+
+/// Something that happened on the connection, queued by poll for whoever drives it
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProtocolEvent {
+    /// The handshake finished, with a resumed session or a new one
+    Connected { resumed: bool },
+    /// A user's speaking flags changed
+    Speaking {
+        user_id: u64,
+        ssrc: u32,
+        speaking: u8,
+    },
+    /// A heartbeat was acknowledged after the given number of seconds
+    Latency(f64),
+    /// A user joined the channel, sending audio with the given SSRC
+    ClientConnect { user_id: u64, ssrc: u32 },
+    /// A user left the channel
+    ClientDisconnect(u64),
+    /// A heartbeat acknowledgement carried another nonce than the last heartbeat sent
+    HeartbeatMismatch { expected: u64, received: u64 },
 }
 
 pub struct ProtocolBuilder {
@@ -43,6 +344,142 @@ pub struct ProtocolBuilder {
     server_id: String,
     session_id: String,
     token: String,
+    reconnect_policy: ReconnectPolicy,
+    tls: TlsOptions,
+    preferred_encryption: Option<EncryptionMode>,
+    max_message_size: usize,
+    preferred_family: Option<IpFamily>,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+}
+
+/// How the certificate of the voice websocket is verified.
+/// The defaults verify it against the system's root certificates.
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+    /// Accepts any certificate, including self-signed, expired and mismatched ones.
+    /// DANGER: this lets anyone in the middle read and tamper with the connection,
+    /// including the token and the secret key. Only use it for testing against a
+    /// local mock voice server.
+    pub danger_accept_invalid_certs: bool,
+    /// A PEM encoded CA certificate trusted in addition to the system's,
+    /// e.g. for a corporate proxy that re-signs traffic with its own CA
+    pub root_certificate: Option<Vec<u8>>,
+}
+
+impl TlsOptions {
+    fn connector(&self) -> Result<TlsConnector, ProtocolError> {
+        let mut builder = TlsConnector::builder();
+        builder.danger_accept_invalid_certs(self.danger_accept_invalid_certs);
+        if let Some(ref pem) = self.root_certificate {
+            builder.add_root_certificate(Certificate::from_pem(pem)?);
+        }
+        Ok(builder.build()?)
+    }
+}
+
+/// An IP address family, for preferring one when an endpoint resolves to both
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpFamily {
+    V4,
+    V6,
+}
+
+impl IpFamily {
+    fn matches(self, addr: &SocketAddr) -> bool {
+        match self {
+            IpFamily::V4 => addr.is_ipv4(),
+            IpFamily::V6 => addr.is_ipv6(),
+        }
+    }
+}
+
+/// Tries each address in turn until one connects, so an endpoint with broken IPv6
+/// still connects over IPv4. Addresses of the preferred family are tried first.
+/// If none connect, the last error is returned along with the addresses tried.
+fn connect_any(
+    mut addrs: Vec<SocketAddr>,
+    preferred: Option<IpFamily>,
+    timeout: Duration,
+) -> Result<TcpStream, ProtocolError> {
+    if let Some(family) = preferred {
+        // The sort is stable so the resolver's order is kept within each family
+        addrs.sort_by_key(|addr| !family.matches(addr));
+    }
+
+    let mut last_error = None;
+    for addr in &addrs {
+        match TcpStream::connect_timeout(addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    let last_error = last_error
+        .unwrap_or_else(|| std::io::Error::new(ErrorKind::NotFound, "no addresses found"));
+    let tried: Vec<String> = addrs.iter().map(ToString::to_string).collect();
+    let message = format!("{} (tried {})", last_error, tried.join(", "));
+    Err(connect_error(std::io::Error::new(
+        last_error.kind(),
+        message,
+    )))
+}
+
+fn handshake_timeout(timeout: Duration) -> ProtocolError {
+    let message = format!("handshake did not finish within {:?}", timeout);
+    ProtocolError::Timeout(std::io::Error::new(ErrorKind::TimedOut, message))
+}
+
+fn connect_websocket(
+    endpoint: &str,
+    tls: &TlsOptions,
+    max_message_size: usize,
+    preferred_family: Option<IpFamily>,
+    connect_timeout: Duration,
+) -> Result<WebSocket<TlsStream<TcpStream>>, ProtocolError> {
+    let connector = tls.connector()?;
+    // Resolving separately is the only way to tell a failed lookup from other errors
+    let addrs: Vec<SocketAddr> = (endpoint, 443)
+        .to_socket_addrs()
+        .map_err(ProtocolError::Dns)?
+        .collect();
+    if addrs.is_empty() {
+        let inner = std::io::Error::new(ErrorKind::NotFound, "no addresses found");
+        return Err(ProtocolError::Dns(inner));
+    }
+    let stream = connect_any(addrs, preferred_family, connect_timeout)?;
+    // The handshakes are bounded by the connect timeout rather than the read timeout,
+    // which is far shorter since it only has to keep poll responsive
+    stream.set_read_timeout(Some(connect_timeout))?;
+    stream.set_write_timeout(Some(connect_timeout))?;
+    let stream = match connector.connect(endpoint, stream) {
+        Ok(stream) => stream,
+        Err(native_tls::HandshakeError::WouldBlock(_)) => {
+            return Err(handshake_timeout(connect_timeout))
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let mut url = String::from("wss://");
+    url.push_str(endpoint);
+    url.push_str("/?v=4");
+    println!("Connecting to {:?}", &url);
+    // Tungstenite refuses oversized frames before buffering them
+    let config = WebSocketConfig {
+        max_message_size: Some(max_message_size),
+        max_frame_size: Some(max_message_size),
+        ..WebSocketConfig::default()
+    };
+    let ws = match tungstenite::client::client_with_config(&url, stream, Some(config)) {
+        Ok((ws, _)) => ws,
+        Err(tungstenite::HandshakeError::Interrupted(_)) => {
+            return Err(handshake_timeout(connect_timeout))
+        }
+        Err(e) => return Err(custom_error(e.to_string().as_str())),
+    };
+    // Reads keep the connect timeout until HELLO arrives, after which poll switches
+    // to the read timeout
+    ws.get_ref().get_ref().set_write_timeout(None)?;
+    Ok(ws)
 }
 
 impl ProtocolBuilder {
@@ -53,6 +490,13 @@ impl ProtocolBuilder {
             server_id: String::new(),
             session_id: String::new(),
             token: String::new(),
+            reconnect_policy: ReconnectPolicy::default(),
+            tls: TlsOptions::default(),
+            preferred_encryption: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            preferred_family: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            read_timeout: POLL_TIMEOUT,
         }
     }
 
@@ -76,22 +520,72 @@ impl ProtocolBuilder {
         self
     }
 
+    pub fn reconnect_policy(&mut self, policy: ReconnectPolicy) -> &mut Self {
+        self.reconnect_policy = policy;
+        self
+    }
+
+    /// Pins the encryption mode, which is used instead of the best one
+    /// whenever the voice server offers it
+    pub fn preferred_encryption(&mut self, mode: Option<EncryptionMode>) -> &mut Self {
+        self.preferred_encryption = mode;
+        self
+    }
+
+    /// Sets how the websocket's certificate is verified, for this and later connections
+    pub fn tls(&mut self, options: TlsOptions) -> &mut Self {
+        self.tls = options;
+        self
+    }
+
+    /// Sets the largest websocket message that's accepted, in bytes.
+    /// Anything larger closes the connection with a MessageTooLarge error.
+    pub fn max_message_size(&mut self, size: usize) -> &mut Self {
+        self.max_message_size = size;
+        self
+    }
+
+    /// Tries addresses of the given family first when the endpoint resolves to both
+    /// IPv4 and IPv6 ones. By default they're tried in the resolver's order.
+    pub fn preferred_family(&mut self, family: Option<IpFamily>) -> &mut Self {
+        self.preferred_family = family;
+        self
+    }
+
+    /// Sets how long connecting to each of the endpoint's addresses may take,
+    /// with the TLS and websocket handshakes and waiting for HELLO each getting
+    /// as long again
+    pub fn connect_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Sets how long a poll waits for a websocket message once HELLO has arrived.
+    /// This is also the longest a queued payload waits to be written, so it should be
+    /// kept short.
+    pub fn read_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.read_timeout = timeout;
+        self
+    }
+
     pub fn connect(self) -> Result<DiscordVoiceProtocol, ProtocolError> {
-        let ws = {
-            let connector = TlsConnector::new()?;
-            let stream = TcpStream::connect((self.endpoint.as_str(), 443))?;
-            let stream = connector.connect(&self.endpoint, stream)?;
-            let mut url = String::from("wss://");
-            url.push_str(self.endpoint.as_str());
-            url.push_str("/?v=4");
-            println!("Connecting to {:?}", &url);
-            match tungstenite::client::client(&url, stream) {
-                Ok((ws, _)) => ws,
-                Err(e) => return Err(custom_error(e.to_string().as_str())),
-            }
-        };
+        let ws = connect_websocket(
+            self.endpoint.as_str(),
+            &self.tls,
+            self.max_message_size,
+            self.preferred_family,
+            self.connect_timeout,
+        )?;
+        Ok(self.connect_with(Box::new(ws)))
+    }
+
+    /// Builds the protocol over an already connected transport, e.g. a MockTransport.
+    /// Nothing is sent until the caller polls or goes through finish_flow.
+    /// Reconnects still open a real websocket to the endpoint.
+    pub fn connect_with(self, ws: Box<dyn Transport>) -> DiscordVoiceProtocol {
+        let (outgoing, pending) = unbounded();
 
-        Ok(DiscordVoiceProtocol {
+        DiscordVoiceProtocol {
             endpoint: self.endpoint,
             user_id: self.user_id,
             server_id: self.server_id,
@@ -100,16 +594,42 @@ impl ProtocolBuilder {
             recent_acks: std::collections::VecDeque::with_capacity(20),
             close_code: 0,
             ws,
+            outgoing,
+            pending,
+            last_speaking: Arc::new(Mutex::new(None)),
             socket: None,
+            send_timeout: DEFAULT_SEND_TIMEOUT,
+            keepalive: None,
+            discovered: None,
             heartbeat_interval: std::u64::MAX,
             port: 0,
             ssrc: 0,
             endpoint_ip: String::default(),
             encryption: EncryptionMode::default(),
+            available_modes: Vec::new(),
             last_heartbeat: Instant::now(),
+            last_heartbeat_ack: Instant::now(),
+            heartbeat_nonce: 0,
             secret_key: [0; 32],
             state: Arc::new(PlayingState::default()),
-        })
+            ssrc_map: Arc::new(Mutex::new(HashMap::new())),
+            departed_ssrcs: Arc::new(Mutex::new(Vec::new())),
+            events: Vec::new(),
+            speaking_states: HashMap::new(),
+            reconnect_policy: self.reconnect_policy,
+            tls: self.tls,
+            preferred_encryption: self.preferred_encryption,
+            max_message_size: self.max_message_size,
+            preferred_family: self.preferred_family,
+            connect_timeout: self.connect_timeout,
+            read_timeout: self.read_timeout,
+            reconnect_attempts: 0,
+            resume_sent: None,
+            select_after_resume: false,
+            was_resumed: false,
+            opened_at: Instant::now(),
+            connected_at: None,
+        }
     }
 }
 
@@ -121,20 +641,182 @@ impl DiscordVoiceProtocol {
         }
     }
 
+    /// Our external IP and port as Discord sees them, found by UDP discovery.
+    /// If audio doesn't get through this shows what NAT mapped the socket to.
+    pub fn discovered_address(&self) -> Option<(&str, u16)> {
+        self.discovered
+            .as_ref()
+            .map(|(ip, port)| (ip.as_str(), *port))
+    }
+
+    pub fn server_id(&self) -> &str {
+        &self.server_id
+    }
+
+    pub fn user_id(&self) -> &str {
+        &self.user_id
+    }
+
+    /// Sets up a saved session on a freshly connected protocol so finish_flow(true) can
+    /// resume it. The old socket went away with the old process, so a new one is bound.
+    /// Voice keeps going to the address the old socket was discovered at, so if the new
+    /// one is mapped elsewhere its address is selected once the session is resumed.
+    pub fn restore_session(&mut self, saved: SavedSession) -> Result<(), ProtocolError> {
+        self.ssrc = saved.ssrc;
+        self.port = saved.port;
+        self.endpoint_ip = saved.endpoint_ip;
+        self.encryption = saved.encryption;
+        let address = self.open_socket()?;
+        self.select_after_resume = saved.discovered.as_ref() != Some(&address);
+        self.discovered = Some(address);
+        self.secret_key = saved.secret_key;
+        Ok(())
+    }
+
+    /// Sets the write timeout of the voice socket.
+    /// This applies to the current socket, if any, and to the sockets made after it.
+    pub fn set_send_timeout(&mut self, timeout: Duration) -> Result<(), ProtocolError> {
+        self.send_timeout = timeout;
+        if let Some(ref socket) = self.socket {
+            socket.set_write_timeout(Some(timeout))?;
+        }
+        Ok(())
+    }
+
+    pub fn send_timeout(&self) -> Duration {
+        self.send_timeout
+    }
+
+    /// How long the current websocket has been connected for, since its handshake finished
+    pub fn connected_since(&self) -> Option<Duration> {
+        self.connected_at.map(|at| at.elapsed())
+    }
+
+    /// How long it took from opening the current websocket to finishing the handshake
+    pub fn handshake_duration(&self) -> Option<Duration> {
+        self.connected_at
+            .map(|at| at.duration_since(self.opened_at))
+    }
+
     pub fn clone_state(&self) -> Arc<PlayingState> {
         Arc::clone(&self.state)
     }
 
+    /// A handle for queueing payloads without holding the protocol lock
+    pub fn clone_sender(&self) -> MessageSender {
+        MessageSender {
+            sender: self.outgoing.clone(),
+            last_speaking: Arc::clone(&self.last_speaking),
+        }
+    }
+
+    /// The SSRC to user ID map, shared with the audio receiver
+    pub fn clone_ssrc_map(&self) -> Arc<Mutex<HashMap<u32, u64>>> {
+        Arc::clone(&self.ssrc_map)
+    }
+
+    /// The SSRCs removed from the map by CLIENT_DISCONNECT, which the audio
+    /// receiver drains to drop the decoders of users that have left
+    pub fn clone_departed_ssrcs(&self) -> Arc<Mutex<Vec<u32>>> {
+        Arc::clone(&self.departed_ssrcs)
+    }
+
+    /// Returns the events that have happened since the last call.
+    /// Whatever polls the protocol should take them after every poll.
+    pub fn take_events(&mut self) -> Vec<ProtocolEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Returns the delay before the next reconnect attempt according to the
+    /// reconnect policy, or None if there are no attempts remaining.
+    pub fn next_reconnect_delay(&mut self) -> Option<Duration> {
+        if self.reconnect_attempts >= self.reconnect_policy.max_attempts {
+            return None;
+        }
+
+        let delay = self.reconnect_policy.delay(self.reconnect_attempts);
+        self.reconnect_attempts += 1;
+        Some(delay)
+    }
+
+    /// Opens a new websocket to the same endpoint and resumes the session, or identifies
+    /// a new one if the last close code means it can't be resumed
+    pub fn reconnect(&mut self) -> Result<(), ProtocolError> {
+        // Dropping the session here rather than after connecting means a failed
+        // attempt doesn't leave the next one trying to resume it
+        if !close_code_resumable(self.close_code) {
+            self.resume_sent = None;
+            self.was_resumed = false;
+            self.secret_key = [0; 32];
+        }
+        let resume = self.secret_key.iter().any(|&c| c != 0);
+
+        self.ws = Box::new(connect_websocket(
+            self.endpoint.as_str(),
+            &self.tls,
+            self.max_message_size,
+            self.preferred_family,
+            self.connect_timeout,
+        )?);
+        self.opened_at = Instant::now();
+        self.connected_at = None;
+        self.close_code = 0;
+        self.heartbeat_interval = std::u64::MAX;
+        self.last_heartbeat = Instant::now();
+        self.last_heartbeat_ack = self.last_heartbeat;
+        self.finish_flow(resume)
+    }
+
+    /// Switches to new credentials, e.g. after a voice server update, even if the
+    /// current websocket is still alive. The websocket is closed with a code that keeps
+    /// the session resumable and a new one is opened to the new endpoint, which resumes
+    /// the session or identifies again if it can't.
+    pub fn reconnect_with(
+        &mut self,
+        endpoint: String,
+        token: String,
+        session_id: String,
+    ) -> Result<(), ProtocolError> {
+        // 4000 isn't one of the codes Discord closes with, it just isn't 1000.
+        // The websocket is replaced either way, so failing to close it is ignored.
+        let _ = self.close(4000);
+        self.update_credentials(endpoint, token, session_id);
+        self.reconnect()
+    }
+
+    /// Stores new credentials to be used the next time the connection is reconnected,
+    /// leaving the current websocket as it is.
+    pub fn update_credentials(&mut self, endpoint: String, token: String, session_id: String) {
+        self.endpoint = endpoint;
+        self.token = token;
+        self.session_id = session_id;
+    }
+
     pub fn finish_flow(&mut self, resume: bool) -> Result<(), ProtocolError> {
-        // get the op HELLO
-        self.poll()?;
+        self.wait_for_hello()?;
         if resume {
             self.resume()?;
         } else {
             self.identify()?;
         }
 
-        while self.secret_key.iter().all(|&c| c == 0) {
+        // A resume is done once RESUMED is received, otherwise it falls back to identifying
+        // and has to wait for the new session description like a fresh connection
+        while self.resume_sent.is_some() || self.secret_key.iter().all(|&c| c == 0) {
+            self.poll()?;
+        }
+        Ok(())
+    }
+
+    /// Polls until HELLO has been handled, which is what sets the heartbeat interval.
+    /// Nothing else may be sent before it, however slow the server is to send it.
+    fn wait_for_hello(&mut self) -> Result<(), ProtocolError> {
+        let started = Instant::now();
+        while self.heartbeat_interval == std::u64::MAX {
+            if started.elapsed() >= self.connect_timeout {
+                let inner = std::io::Error::new(ErrorKind::TimedOut, "did not receive HELLO");
+                return Err(ProtocolError::Timeout(inner));
+            }
             self.poll()?;
         }
         Ok(())
@@ -142,26 +824,78 @@ impl DiscordVoiceProtocol {
 
     pub fn close(&mut self, code: u16) -> Result<(), ProtocolError> {
         self.state.disconnected();
+        self.keepalive = None;
         self.close_code = code;
+        // Queued payloads are only written when polling, so anything queued right
+        // before closing (e.g. speaking being turned off) would otherwise never be sent
+        let flushed = self.flush_pending();
         self.ws.close(Some(CloseFrame {
             code: CloseCode::from(code),
             reason: std::borrow::Cow::Owned("closing connection".to_string()),
         }))?;
-        Ok(())
+        flushed
+    }
+
+    /// Whether Discord has stopped acknowledging our heartbeats.
+    /// This is the case when no HEARTBEAT_ACK has been received within two intervals.
+    pub fn heartbeat_timed_out(&self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last_heartbeat_ack);
+        elapsed.as_millis() as u64 > self.heartbeat_interval.saturating_mul(2)
     }
 
     pub fn poll(&mut self) -> Result<(), ProtocolError> {
+        self.poll_with(false).map(|_| ())
+    }
+
+    /// Does the same work as poll, but returns straight away rather than waiting up to
+    /// the read timeout if there's no message to read, so many connections can be driven
+    /// from one thread. Returns whether a message was read.
+    pub fn poll_once(&mut self) -> Result<bool, ProtocolError> {
+        self.poll_with(true)
+    }
+
+    fn poll_with(&mut self, nonblocking: bool) -> Result<bool, ProtocolError> {
+        if self.heartbeat_timed_out(Instant::now()) {
+            // 4009 is the session timeout close code, which lets the reconnect logic take over
+            self.state.disconnected();
+            self.close_code = 4009;
+            return Err(ProtocolError::Closed(
+                self.close_code,
+                Some("heartbeat acknowledgement timed out".to_string()),
+            ));
+        }
+
         if self.last_heartbeat.elapsed().as_millis() as u64 >= self.heartbeat_interval {
             self.heartbeat()?;
         }
 
+        if let Some(sent) = self.resume_sent {
+            if sent.elapsed() >= RESUME_TIMEOUT {
+                self.reidentify()?;
+            }
+        }
+
+        self.flush_pending()?;
+
         let msg = {
-            match self.ws.read_message() {
+            // Tungstenite keeps partially read frames around, so a read can be
+            // interrupted by WouldBlock and carried on by the next poll
+            if nonblocking {
+                self.ws.set_nonblocking(true)?;
+            }
+            let result = self.ws.read_message();
+            if nonblocking {
+                self.ws.set_nonblocking(false)?;
+            }
+            match result {
                 Err(TungError::Io(ref e))
                     if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut =>
                 {
                     // We'll just continue reading since we timed out?
-                    return Ok(());
+                    return Ok(false);
+                }
+                Err(TungError::Capacity(_)) => {
+                    return Err(ProtocolError::MessageTooLarge(self.max_message_size));
                 }
                 Err(e) => return Err(ProtocolError::from(e)),
                 Ok(msg) => msg,
@@ -169,6 +903,10 @@ impl DiscordVoiceProtocol {
         };
 
         match msg {
+            // Transports other than the websocket don't enforce the limit themselves
+            Message::Text(ref string) if string.len() > self.max_message_size => {
+                return Err(ProtocolError::MessageTooLarge(self.max_message_size));
+            }
             Message::Text(string) => {
                 let payload: RawReceivedPayload = serde_json::from_str(string.as_str())?;
 
@@ -179,12 +917,15 @@ impl DiscordVoiceProtocol {
                 match payload.op {
                     Opcode::HELLO => {
                         let payload: Hello = serde_json::from_str(payload.d.get())?;
-                        let interval = payload.heartbeat_interval as u64;
-                        self.heartbeat_interval = interval.min(5000);
-                        // Get the original stream
-                        let socket = self.ws.get_ref().get_ref();
-                        socket.set_read_timeout(Some(std::time::Duration::from_millis(1000)))?;
+                        // The interval used to be capped at 5 seconds because it doubled as
+                        // the socket read timeout, and a blocking read has to return in time
+                        // for the next heartbeat. Reads now time out after the read timeout
+                        // instead, so poll checks the heartbeat often enough for any interval
+                        // and the one Discord asks for can be used as is.
+                        self.heartbeat_interval = payload.heartbeat_interval as u64;
+                        self.ws.set_read_timeout(Some(self.read_timeout))?;
                         self.last_heartbeat = Instant::now();
+                        self.last_heartbeat_ack = self.last_heartbeat;
                     }
                     Opcode::READY => {
                         let payload: Ready = serde_json::from_str(payload.d.get())?;
@@ -194,18 +935,91 @@ impl DiscordVoiceProtocol {
                         self.heartbeat()?;
                     }
                     Opcode::HEARTBEAT_ACK => {
+                        let payload: HeartbeatAck = serde_json::from_str(payload.d.get())?;
+                        // An acknowledgement of an older heartbeat doesn't say anything
+                        // about the latest one, so it's only reported
+                        if payload.0 != self.heartbeat_nonce {
+                            self.events.push(ProtocolEvent::HeartbeatMismatch {
+                                expected: self.heartbeat_nonce,
+                                received: payload.0,
+                            });
+                            return Ok(true);
+                        }
+
                         let now = Instant::now();
                         let delta = now.duration_since(self.last_heartbeat);
+                        self.last_heartbeat_ack = now;
                         if self.recent_acks.len() == 20 {
                             self.recent_acks.pop_front();
                         }
                         self.recent_acks.push_back(delta.as_secs_f64());
+                        self.events
+                            .push(ProtocolEvent::Latency(delta.as_secs_f64()));
                     }
                     Opcode::SESSION_DESCRIPTION => {
                         let payload: SessionDescription = serde_json::from_str(payload.d.get())?;
                         self.encryption = EncryptionMode::from_str(payload.mode.as_str())?;
                         self.secret_key = payload.secret_key;
-                        self.state.connected();
+                        self.reconnect_attempts = 0;
+                        // Later ones are key rotations rather than the end of a handshake
+                        if self.connected_at.is_none() {
+                            self.connected_at = Some(Instant::now());
+                            self.events
+                                .push(ProtocolEvent::Connected { resumed: false });
+                        }
+                        self.state.key_rotated();
+                        // A session description can also arrive mid-session when the key is
+                        // rotated, in which case the player keeps going with the new key
+                        if self.state.is_disconnected() {
+                            self.state.connected();
+                        }
+                    }
+                    Opcode::RESUMED => {
+                        self.handle_resumed()?;
+                    }
+                    Opcode::SPEAKING => {
+                        let payload: SpeakingUpdate = serde_json::from_str(payload.d.get())?;
+                        let user_id = parse_snowflake(payload.user_id.as_str())?;
+                        self.ssrc_map.lock().insert(payload.ssrc, user_id);
+                        self.speaking_states.insert(user_id, payload.speaking);
+                        self.events.push(ProtocolEvent::Speaking {
+                            user_id,
+                            ssrc: payload.ssrc,
+                            speaking: payload.speaking,
+                        });
+                    }
+                    Opcode::CLIENT_CONNECT => {
+                        // This arrives before the user speaks, so their first packets can
+                        // be attributed without waiting for a SPEAKING payload
+                        let payload: ClientConnect = serde_json::from_str(payload.d.get())?;
+                        let user_id = parse_snowflake(payload.user_id.as_str())?;
+                        let ssrc = payload.audio_ssrc;
+                        let mut departed = self.departed_ssrcs.lock();
+                        let mut ssrc_map = self.ssrc_map.lock();
+                        // A user that rejoins gets a new SSRC, so the old one is dropped
+                        ssrc_map.retain(|old, id| {
+                            if *id == user_id && *old != ssrc {
+                                departed.push(*old);
+                                return false;
+                            }
+                            true
+                        });
+                        ssrc_map.insert(ssrc, user_id);
+                        self.events
+                            .push(ProtocolEvent::ClientConnect { user_id, ssrc });
+                    }
+                    Opcode::CLIENT_DISCONNECT => {
+                        let payload: ClientDisconnect = serde_json::from_str(payload.d.get())?;
+                        let user_id = parse_snowflake(payload.user_id.as_str())?;
+                        let mut departed = self.departed_ssrcs.lock();
+                        self.ssrc_map.lock().retain(|ssrc, id| {
+                            if *id == user_id {
+                                departed.push(*ssrc);
+                            }
+                            *id != user_id
+                        });
+                        self.speaking_states.remove(&user_id);
+                        self.events.push(ProtocolEvent::ClientDisconnect(user_id));
                     }
                     // The rest are unhandled for now
                     _ => {}
@@ -213,15 +1027,36 @@ impl DiscordVoiceProtocol {
             }
             Message::Close(msg) => {
                 println!("Received close frame: {:?}", &msg);
+                let mut reason = None;
                 if let Some(frame) = msg {
                     self.close_code = u16::from(frame.code);
+                    if !frame.reason.is_empty() {
+                        reason = Some(frame.reason.into_owned());
+                    }
                 }
                 self.state.disconnected();
-                return Err(ProtocolError::Closed(self.close_code));
+                return Err(ProtocolError::Closed(self.close_code, reason));
             }
-            _ => {}
+            // Tungstenite queues a pong of its own, but only sends it on the next write
+            // and a transport other than the websocket doesn't do it at all, so the ping
+            // is answered right away. A second pong is harmless.
+            Message::Ping(data) => {
+                self.ws.write_message(Message::Pong(data))?;
+            }
+            Message::Pong(_) => {}
+            // Fragmented messages are put back together by tungstenite, so a binary
+            // message here is a whole one. Discord never sends these.
+            Message::Binary(_) => {}
         }
 
+        Ok(true)
+    }
+
+    /// Writes the payloads queued through a MessageSender
+    fn flush_pending(&mut self) -> Result<(), ProtocolError> {
+        while let Ok(msg) = self.pending.try_recv() {
+            self.ws.write_message(msg)?;
+        }
         Ok(())
     }
 
@@ -238,7 +1073,8 @@ impl DiscordVoiceProtocol {
     }
 
     fn heartbeat(&mut self) -> Result<(), ProtocolError> {
-        let msg = Heartbeat::now();
+        self.heartbeat_nonce = self.heartbeat_nonce.wrapping_add(1);
+        let msg = Heartbeat::new(self.heartbeat_nonce);
         println!("Heatbeating... {:?}", &msg);
         self.ws
             .write_message(Message::text(serde_json::to_string(&msg)?))?;
@@ -259,6 +1095,15 @@ impl DiscordVoiceProtocol {
         Ok(())
     }
 
+    /// Gives up on resuming and starts a new session over the same websocket.
+    /// The secret key is cleared since a new one comes with the new session.
+    fn reidentify(&mut self) -> Result<(), ProtocolError> {
+        self.resume_sent = None;
+        self.was_resumed = false;
+        self.secret_key = [0; 32];
+        self.identify()
+    }
+
     fn resume(&mut self) -> Result<(), ProtocolError> {
         let msg = Resume::new(ResumeInfo {
             token: self.token.clone(),
@@ -268,14 +1113,77 @@ impl DiscordVoiceProtocol {
         println!("Resuming... {:?}", &msg);
         self.ws
             .write_message(Message::text(serde_json::to_string(&msg)?))?;
+        self.resume_sent = Some(Instant::now());
+        self.was_resumed = false;
         Ok(())
     }
 
     fn handle_ready(&mut self, payload: Ready) -> Result<(), ProtocolError> {
         self.ssrc = payload.ssrc;
         self.port = payload.port;
-        self.encryption = payload.get_encryption_mode()?;
+        self.encryption = payload.select_encryption_mode(self.preferred_encryption)?;
+        self.available_modes = payload.modes.clone();
         self.endpoint_ip = payload.ip;
+        // A READY means a new session so the old socket and its address can't be reused
+        self.discovered = None;
+        self.select_after_resume = false;
+        let (ip, port) = self.open_socket()?;
+        self.discovered = Some((ip.clone(), port));
+
+        self.keepalive = Some(UdpKeepalive::new(
+            self.clone_socket()?,
+            self.ssrc,
+            self.clone_state(),
+        ));
+
+        // select protocol
+        let to_send = SelectProtocol::from_addr(ip, port, self.encryption);
+        self.ws
+            .write_message(Message::text(serde_json::to_string(&to_send)?))?;
+        Ok(())
+    }
+
+    /// A resumed session keeps its UDP mapping, so the socket and the address found by
+    /// UDP discovery are reused rather than binding a new socket and discovering again.
+    /// If there is nothing to reuse then a new session is identified instead, which
+    /// goes through READY and sets up the socket from scratch.
+    fn handle_resumed(&mut self) -> Result<(), ProtocolError> {
+        if self.socket.is_none() || self.discovered.is_none() {
+            return self.reidentify();
+        }
+
+        self.resume_sent = None;
+        self.was_resumed = true;
+        self.connected_at = Some(Instant::now());
+        self.events.push(ProtocolEvent::Connected { resumed: true });
+
+        if self.select_after_resume {
+            self.select_after_resume = false;
+            if let Some((ip, port)) = self.discovered.clone() {
+                let to_send = SelectProtocol::from_addr(ip, port, self.encryption);
+                self.ws
+                    .write_message(Message::text(serde_json::to_string(&to_send)?))?;
+            }
+        }
+
+        if self.keepalive.is_none() {
+            self.keepalive = Some(UdpKeepalive::new(
+                self.clone_socket()?,
+                self.ssrc,
+                self.clone_state(),
+            ));
+        }
+
+        self.reconnect_attempts = 0;
+        if self.state.is_disconnected() {
+            self.state.connected();
+        }
+        Ok(())
+    }
+
+    /// Binds a new voice socket to the voice server's address and port and returns
+    /// the external address UDP discovery finds for it
+    fn open_socket(&mut self) -> Result<(String, u16), ProtocolError> {
         let addr = SocketAddr::new(
             IpAddr::V4(self.endpoint_ip.as_str().parse::<Ipv4Addr>()?),
             self.port,
@@ -283,33 +1191,15 @@ impl DiscordVoiceProtocol {
         println!("Address found: {:?}", &addr);
         // I'm unsure why I have to explicitly bind with Rust
         let socket = UdpSocket::bind("0.0.0.0:0")?;
-        socket.connect(&addr)?;
+        socket.connect(addr)?;
+        // Without a write timeout send_to blocks when the send buffer is full.
+        // The timeout is on the socket itself so it applies to every clone of it.
+        socket.set_write_timeout(Some(self.send_timeout))?;
         self.socket = Some(socket);
 
-        // attempt to do this up to 5 times
-        let (ip, port) = {
-            let mut retries = 0;
-            loop {
-                match self.udp_discovery() {
-                    Ok(x) => break x,
-                    Err(e) => {
-                        if retries < 5 {
-                            retries += 1;
-                            continue;
-                        }
-                        return Err(e);
-                    }
-                }
-            }
-        };
-
+        let (ip, port) = self.discover_with_retries()?;
         println!("UDP discovery found: {}:{}", &ip, &port);
-
-        // select protocol
-        let to_send = SelectProtocol::from_addr(ip, port, self.encryption);
-        self.ws
-            .write_message(Message::text(serde_json::to_string(&to_send)?))?;
-        Ok(())
+        Ok((ip, port))
     }
 
     fn get_socket<'a>(&'a self) -> Result<&'a UdpSocket, ProtocolError> {
@@ -319,43 +1209,60 @@ impl DiscordVoiceProtocol {
         }
     }
 
+    /// Attempts UDP discovery up to DISCOVERY_ATTEMPTS times with a backoff in between.
+    /// A lost packet only costs DISCOVERY_RECV_TIMEOUT rather than blocking forever.
+    fn discover_with_retries(&mut self) -> Result<(String, u16), ProtocolError> {
+        self.get_socket()?
+            .set_read_timeout(Some(DISCOVERY_RECV_TIMEOUT))?;
+        let mut delay = DISCOVERY_RETRY_DELAY;
+        let mut attempt = 1;
+        let result = loop {
+            if let Ok(address) = self.udp_discovery() {
+                break Ok(address);
+            }
+            if attempt == DISCOVERY_ATTEMPTS {
+                break Err(ProtocolError::UdpDiscovery(attempt));
+            }
+            thread::sleep(delay);
+            delay *= 2;
+            attempt += 1;
+        };
+
+        // The socket is cloned for the player and the receiver, which set their own timeouts
+        self.get_socket()?.set_read_timeout(None)?;
+        result
+    }
+
     fn udp_discovery(&mut self) -> Result<(String, u16), ProtocolError> {
         let socket = self.get_socket()?;
         // Generate a packet
-        let mut buffer: [u8; 70] = [0; 70];
-        buffer[0..2].copy_from_slice(&1u16.to_be_bytes()); // 1 = send
-        buffer[2..4].copy_from_slice(&70u16.to_be_bytes()); // 70 = length
+        let mut buffer = [0u8; DISCOVERY_PACKET_SIZE];
+        buffer[0..2].copy_from_slice(&DISCOVERY_REQUEST.to_be_bytes());
+        buffer[2..4].copy_from_slice(&DISCOVERY_LENGTH.to_be_bytes());
         buffer[4..8].copy_from_slice(&self.ssrc.to_be_bytes()); // the SSRC
 
         // rest of this is unused
         // let's send the packet
         socket.send(&buffer)?;
 
-        // receive the new buffer
-        let mut buffer: [u8; 70] = [0; 70];
-        socket.recv(&mut buffer)?;
-
-        // The IP is surrounded by 4 leading bytes and ends on the first encounter of a null byte
-        let ip_end = &buffer[4..]
-            .iter()
-            .position(|&b| b == 0)
-            .ok_or_else(|| custom_error("could not find end of IP"))?;
-        let ip: String = {
-            let ip_slice = &buffer[4..4 + ip_end];
-            let as_str = std::str::from_utf8(ip_slice)
-                .map_err(|_| custom_error("invalid IP found (not UTF-8"))?;
-            String::from(as_str)
-        };
-        // The port is the last 2 bytes in big endian
-        // can't use regular slices with this API
-        let port = u16::from_be_bytes([buffer[68], buffer[69]]);
-        Ok((ip, port))
+        // receive the new buffer, which is a full datagram's worth so a packet of
+        // the wrong size is seen as such rather than silently truncated
+        let mut buffer = [0u8; 1500];
+        let size = socket.recv(&mut buffer)?;
+        parse_discovery_response(&buffer[..size], self.ssrc)
     }
 
-    pub fn speaking(&mut self, flags: SpeakingFlags) -> Result<(), ProtocolError> {
-        let msg: Speaking = Speaking::new(flags);
+    /// Writes the speaking flags straight away, skipping them if they were the last
+    /// ones sent unless forced. See MessageSender::speaking.
+    pub fn speaking(&mut self, flags: SpeakingFlags, force: bool) -> Result<(), ProtocolError> {
+        let mut last = self.last_speaking.lock();
+        if !force && last.as_ref() == Some(&flags) {
+            return Ok(());
+        }
+        let msg: Speaking = Speaking::new(flags.clone(), 0);
         self.ws
             .write_message(Message::text(serde_json::to_string(&msg)?))?;
+        *last = Some(flags);
         Ok(())
     }
 
@@ -363,3 +1270,314 @@ impl DiscordVoiceProtocol {
         Ok(())
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::transport::MockTransport;
+
+    use serde_json::Value;
+
+    pub(crate) const HELLO: &str = r#"{"op": 8, "d": {"heartbeat_interval": 41250}}"#;
+
+    pub(crate) fn protocol(mock: &MockTransport) -> DiscordVoiceProtocol {
+        let mut builder = ProtocolBuilder::new("voice.example.com".to_string());
+        builder
+            .user("1".to_string())
+            .server("2".to_string())
+            .session("session".to_string())
+            .auth("token".to_string());
+        builder.connect_with(Box::new(mock.clone()))
+    }
+
+    /// The payloads written since the last call, parsed back into JSON
+    fn sent_payloads(mock: &MockTransport) -> Vec<Value> {
+        mock.take_sent()
+            .into_iter()
+            .map(|msg| match msg {
+                Message::Text(text) => serde_json::from_str(&text).unwrap(),
+                other => panic!("expected a text message, got {:?}", other),
+            })
+            .collect()
+    }
+
+    fn discovery_response(ssrc: u32, ip: &str, port: u16) -> [u8; DISCOVERY_PACKET_SIZE] {
+        let mut buffer = [0u8; DISCOVERY_PACKET_SIZE];
+        buffer[0..2].copy_from_slice(&DISCOVERY_RESPONSE.to_be_bytes());
+        buffer[2..4].copy_from_slice(&DISCOVERY_LENGTH.to_be_bytes());
+        buffer[4..8].copy_from_slice(&ssrc.to_be_bytes());
+        buffer[8..8 + ip.len()].copy_from_slice(ip.as_bytes());
+        buffer[72..74].copy_from_slice(&port.to_be_bytes());
+        buffer
+    }
+
+    /// Answers a single UDP discovery request on a local port the way the voice
+    /// server does, returning the port to put in READY. The thread hands the
+    /// socket back once it has answered so tests can read what is sent to it.
+    pub(crate) fn discovery_responder(
+        ip: &'static str,
+        port: u16,
+    ) -> (u16, thread::JoinHandle<UdpSocket>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let local_port = socket.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let mut request = [0u8; DISCOVERY_PACKET_SIZE];
+            let (_, from) = socket.recv_from(&mut request).unwrap();
+            let ssrc = u32::from_be_bytes([request[4], request[5], request[6], request[7]]);
+            let response = discovery_response(ssrc, ip, port);
+            socket.send_to(&response, from).unwrap();
+            socket
+        });
+        (local_port, handle)
+    }
+
+    #[test]
+    fn handshake() {
+        let mock = MockTransport::new();
+        let mut protocol = protocol(&mock);
+        let state = protocol.clone_state();
+
+        mock.push_text(HELLO);
+        assert!(protocol.poll_once().unwrap());
+        assert_eq!(protocol.heartbeat_interval, 41250);
+        assert!(mock.take_sent().is_empty());
+
+        protocol.identify().unwrap();
+        let sent = sent_payloads(&mock);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0]["op"], Opcode::IDENTIFY);
+        assert_eq!(sent[0]["d"]["session_id"], "session");
+
+        let (port, _) = discovery_responder("203.0.113.5", 50000);
+        mock.push_text(&format!(
+            r#"{{"op": 2, "d": {{"ssrc": 1234, "ip": "127.0.0.1", "port": {}, "modes": ["xsalsa20_poly1305", "aead_xchacha20_poly1305_rtpsize"], "heartbeat_interval": 1}}}}"#,
+            port
+        ));
+        assert!(protocol.poll_once().unwrap());
+        assert_eq!(protocol.ssrc, 1234);
+        assert_eq!(protocol.discovered_address(), Some(("203.0.113.5", 50000)));
+        assert!(state.is_disconnected());
+
+        let sent = sent_payloads(&mock);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0]["op"], Opcode::SELECT_PROTOCOL);
+        assert_eq!(sent[0]["d"]["data"]["address"], "203.0.113.5");
+        assert_eq!(sent[0]["d"]["data"]["port"], 50000);
+        assert_eq!(
+            sent[0]["d"]["data"]["mode"],
+            "aead_xchacha20_poly1305_rtpsize"
+        );
+
+        let mut key = [0u8; 32];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = i as u8 + 1;
+        }
+        mock.push_text(&format!(
+            r#"{{"op": 4, "d": {{"mode": "aead_xchacha20_poly1305_rtpsize", "secret_key": {}}}}}"#,
+            serde_json::to_string(&key).unwrap()
+        ));
+        assert!(protocol.poll_once().unwrap());
+        assert_eq!(protocol.secret_key, key);
+        assert!(protocol.encryption == EncryptionMode::AeadXChaCha20Poly1305RtpSize);
+        assert!(state.is_connected());
+        assert_eq!(state.key_generation(), 1);
+        assert_eq!(
+            protocol.take_events(),
+            vec![ProtocolEvent::Connected { resumed: false }]
+        );
+        assert!(protocol.handshake_duration().is_some());
+    }
+
+    #[test]
+    fn restored_session_is_resumed() {
+        // The second restore's socket is discovered somewhere other than the saved address
+        let cases = [("203.0.113.5", 50000), ("203.0.113.5", 50001)];
+        for &(ip, discovered_port) in &cases {
+            let mock = MockTransport::new();
+            let mut protocol = protocol(&mock);
+            let (port, _) = discovery_responder(ip, discovered_port);
+            let saved = SavedSession {
+                endpoint_ip: "127.0.0.1".to_string(),
+                port,
+                ssrc: 1234,
+                encryption: EncryptionMode::AeadXChaCha20Poly1305RtpSize,
+                secret_key: [7; 32],
+                discovered: Some(("203.0.113.5".to_string(), 50000)),
+            };
+            protocol.restore_session(saved).unwrap();
+            assert_eq!(protocol.discovered_address(), Some((ip, discovered_port)));
+
+            mock.push_text(HELLO);
+            mock.push_text(r#"{"op": 9, "d": null}"#);
+            protocol.finish_flow(true).unwrap();
+            assert!(protocol.was_resumed);
+            assert!(protocol.clone_state().is_connected());
+
+            let sent = sent_payloads(&mock);
+            assert_eq!(sent[0]["op"], Opcode::RESUME);
+            assert_eq!(sent[0]["d"]["session_id"], "session");
+            if discovered_port == 50000 {
+                assert_eq!(sent.len(), 1);
+            } else {
+                assert_eq!(sent.len(), 2);
+                assert_eq!(sent[1]["op"], Opcode::SELECT_PROTOCOL);
+                assert_eq!(sent[1]["d"]["data"]["port"], discovered_port);
+            }
+        }
+    }
+
+    #[test]
+    fn speaking_is_only_sent_when_it_changes() {
+        let mock = MockTransport::new();
+        let mut protocol = protocol(&mock);
+        mock.push_text(HELLO);
+        protocol.poll_once().unwrap();
+
+        protocol
+            .speaking(SpeakingFlags::microphone(), false)
+            .unwrap();
+        let sent = sent_payloads(&mock);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0]["op"], Opcode::SPEAKING);
+        assert_eq!(sent[0]["d"]["speaking"], SpeakingFlags::MICROPHONE);
+        assert_eq!(sent[0]["d"]["delay"], 0);
+
+        protocol
+            .speaking(SpeakingFlags::microphone(), false)
+            .unwrap();
+        assert!(mock.take_sent().is_empty());
+
+        protocol
+            .speaking(SpeakingFlags::microphone(), true)
+            .unwrap();
+        assert_eq!(sent_payloads(&mock).len(), 1);
+
+        // A sender shares what was last sent and its payloads are written by poll
+        let sender = protocol.clone_sender();
+        sender.speaking(SpeakingFlags::microphone(), false).unwrap();
+        sender.speaking(SpeakingFlags::off(), false).unwrap();
+        assert!(mock.take_sent().is_empty());
+        assert!(!protocol.poll_once().unwrap());
+        let sent = sent_payloads(&mock);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0]["d"]["speaking"], 0);
+    }
+
+    #[test]
+    fn ping_is_answered_with_pong() {
+        let mock = MockTransport::new();
+        let mut protocol = protocol(&mock);
+
+        mock.push(Message::Ping(vec![1, 2, 3]));
+        assert!(protocol.poll_once().unwrap());
+        assert_eq!(mock.take_sent(), vec![Message::Pong(vec![1, 2, 3])]);
+    }
+
+    #[test]
+    fn oversized_text_is_refused() {
+        let mock = MockTransport::new();
+        let mut builder = ProtocolBuilder::new("voice.example.com".to_string());
+        builder.max_message_size(16);
+        let mut protocol = builder.connect_with(Box::new(mock.clone()));
+
+        mock.push_text(HELLO);
+        match protocol.poll_once() {
+            Err(ProtocolError::MessageTooLarge(16)) => {}
+            other => panic!("expected MessageTooLarge(16), got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn mismatched_heartbeat_ack_is_reported() {
+        let mock = MockTransport::new();
+        let mut protocol = protocol(&mock);
+        mock.push_text(HELLO);
+        protocol.poll_once().unwrap();
+        protocol.heartbeat().unwrap();
+        mock.take_sent();
+
+        let expected = protocol.heartbeat_nonce;
+        let ack = protocol.last_heartbeat_ack;
+        mock.push_text(&format!(r#"{{"op": 6, "d": {}}}"#, expected + 1));
+        assert!(protocol.poll_once().unwrap());
+        assert_eq!(
+            protocol.take_events(),
+            vec![ProtocolEvent::HeartbeatMismatch {
+                expected,
+                received: expected + 1,
+            }]
+        );
+        // It doesn't count as an acknowledgement of the latest heartbeat
+        assert_eq!(protocol.last_heartbeat_ack, ack);
+
+        mock.push_text(&format!(r#"{{"op": 6, "d": {}}}"#, expected));
+        assert!(protocol.poll_once().unwrap());
+        match protocol.take_events().as_slice() {
+            [ProtocolEvent::Latency(_)] => {}
+            other => panic!("expected a latency event, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn heartbeat_times_out_after_two_intervals() {
+        let mock = MockTransport::new();
+        let mut protocol = protocol(&mock);
+        mock.push_text(HELLO);
+        protocol.poll_once().unwrap();
+
+        let ack = protocol.last_heartbeat_ack;
+        let limit = Duration::from_millis(2 * 41250);
+        assert!(!protocol.heartbeat_timed_out(ack));
+        assert!(!protocol.heartbeat_timed_out(ack + limit - Duration::from_millis(1)));
+        assert!(protocol.heartbeat_timed_out(ack + limit + Duration::from_millis(1)));
+    }
+
+    #[test]
+    fn poll_closes_with_4009_when_heartbeats_time_out() {
+        let mock = MockTransport::new();
+        let mut protocol = protocol(&mock);
+        let state = protocol.clone_state();
+        mock.push_text(r#"{"op": 8, "d": {"heartbeat_interval": 10}}"#);
+        protocol.poll_once().unwrap();
+        state.connected();
+
+        thread::sleep(Duration::from_millis(30));
+        match protocol.poll_once() {
+            Err(ProtocolError::Closed(4009, _)) => {}
+            other => panic!("expected Closed(4009, _), got {:?}", other),
+        }
+        assert!(state.is_disconnected());
+    }
+
+    #[test]
+    fn discovery_response_is_parsed() {
+        let response = discovery_response(1234, "203.0.113.5", 50000);
+        let (ip, port) = parse_discovery_response(&response, 1234).unwrap();
+        assert_eq!(ip, "203.0.113.5");
+        assert_eq!(port, 50000);
+    }
+
+    #[test]
+    fn invalid_discovery_responses_are_refused() {
+        let mut wrong_type = discovery_response(1234, "203.0.113.5", 50000);
+        wrong_type[0..2].copy_from_slice(&DISCOVERY_REQUEST.to_be_bytes());
+        assert!(parse_discovery_response(&wrong_type, 1234).is_err());
+
+        let mut wrong_length = discovery_response(1234, "203.0.113.5", 50000);
+        wrong_length[2..4].copy_from_slice(&74u16.to_be_bytes());
+        assert!(parse_discovery_response(&wrong_length, 1234).is_err());
+
+        let wrong_ssrc = discovery_response(4321, "203.0.113.5", 50000);
+        assert!(parse_discovery_response(&wrong_ssrc, 1234).is_err());
+
+        let valid = discovery_response(1234, "203.0.113.5", 50000);
+        assert!(parse_discovery_response(&valid[..DISCOVERY_PACKET_SIZE - 1], 1234).is_err());
+
+        let mut longer = valid.to_vec();
+        longer.push(0);
+        assert!(parse_discovery_response(&longer, 1234).is_err());
+
+        let bad_ip = discovery_response(1234, "not an ip", 50000);
+        assert!(parse_discovery_response(&bad_ip, 1234).is_err());
+    }
+}
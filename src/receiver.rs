@@ -0,0 +1,353 @@
+use crate::error::ProtocolError;
+use crate::payloads::EncryptionMode;
+use crate::player::{InPlaceBuffer, VoiceCipher, CHANNELS, MAX_BUFFER_SIZE};
+use crate::protocol::DiscordVoiceProtocol;
+
+use parking_lot::Mutex;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::UdpSocket;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use xsalsa20poly1305::aead::Buffer;
+
+pub const RTP_HEADER_SIZE: usize = 12;
+
+/// The largest Opus frame is 120ms, which is 5760 samples per channel at 48kHz
+pub const MAX_DECODED_SAMPLES: usize = 5760 * CHANNELS as usize;
+
+type Protocol = Arc<Mutex<DiscordVoiceProtocol>>;
+pub(crate) type Decrypter =
+    fn(&VoiceCipher, &[u8], &mut [u8]) -> Result<usize, xsalsa20poly1305::aead::Error>;
+
+/// Copies the encrypted payload into the buffer and decrypts it in-place.
+/// Returns the size of the decrypted data at the start of the buffer.
+fn decrypt_into(
+    cipher: &VoiceCipher,
+    nonce: &[u8; 24],
+    associated_data: &[u8],
+    data: &[u8],
+    buffer: &mut [u8],
+) -> Result<usize, xsalsa20poly1305::aead::Error> {
+    if data.len() > buffer.len() {
+        return Err(xsalsa20poly1305::aead::Error);
+    }
+
+    buffer[..data.len()].copy_from_slice(data);
+    let mut buffer = InPlaceBuffer::new(buffer, data.len());
+    cipher.decrypt_in_place(nonce, associated_data, &mut buffer)?;
+    Ok(buffer.len())
+}
+
+fn decrypt_xsalsa20_poly1305(
+    cipher: &VoiceCipher,
+    packet: &[u8],
+    buffer: &mut [u8],
+) -> Result<usize, xsalsa20poly1305::aead::Error> {
+    let mut nonce: [u8; 24] = [0; 24];
+    nonce[0..RTP_HEADER_SIZE].copy_from_slice(&packet[0..RTP_HEADER_SIZE]);
+    decrypt_into(cipher, &nonce, b"", &packet[RTP_HEADER_SIZE..], buffer)
+}
+
+fn decrypt_xsalsa20_poly1305_suffix(
+    cipher: &VoiceCipher,
+    packet: &[u8],
+    buffer: &mut [u8],
+) -> Result<usize, xsalsa20poly1305::aead::Error> {
+    if packet.len() < RTP_HEADER_SIZE + 24 {
+        return Err(xsalsa20poly1305::aead::Error);
+    }
+
+    let (data, suffix) = packet[RTP_HEADER_SIZE..].split_at(packet.len() - RTP_HEADER_SIZE - 24);
+    let mut nonce: [u8; 24] = [0; 24];
+    nonce.copy_from_slice(suffix);
+    decrypt_into(cipher, &nonce, b"", data, buffer)
+}
+
+fn decrypt_xsalsa20_poly1305_lite(
+    cipher: &VoiceCipher,
+    packet: &[u8],
+    buffer: &mut [u8],
+) -> Result<usize, xsalsa20poly1305::aead::Error> {
+    if packet.len() < RTP_HEADER_SIZE + 4 {
+        return Err(xsalsa20poly1305::aead::Error);
+    }
+
+    let (data, suffix) = packet[RTP_HEADER_SIZE..].split_at(packet.len() - RTP_HEADER_SIZE - 4);
+    let mut nonce: [u8; 24] = [0; 24];
+    nonce[0..4].copy_from_slice(suffix);
+    decrypt_into(cipher, &nonce, b"", data, buffer)
+}
+
+/// The size of the part of the RTP header that is sent in the clear by the rtpsize modes.
+/// This is the fixed header, the CSRCs and the extension header but not the extension data.
+fn rtpsize_header_len(packet: &[u8]) -> usize {
+    let csrc_count = (packet[0] & 0x0F) as usize;
+    let has_extension = packet[0] & 0x10 != 0;
+    RTP_HEADER_SIZE + 4 * csrc_count + if has_extension { 4 } else { 0 }
+}
+
+/// The rtpsize modes authenticate the unencrypted header as associated data.
+/// The extension data is encrypted along with the payload so it is skipped
+/// after decrypting, which leaves only the Opus data in the buffer.
+fn decrypt_aead_rtpsize(
+    cipher: &VoiceCipher,
+    packet: &[u8],
+    buffer: &mut [u8],
+) -> Result<usize, xsalsa20poly1305::aead::Error> {
+    let header_len = rtpsize_header_len(packet);
+    if packet.len() < header_len + 4 {
+        return Err(xsalsa20poly1305::aead::Error);
+    }
+
+    let (header, rest) = packet.split_at(header_len);
+    let (data, suffix) = rest.split_at(rest.len() - 4);
+    let mut nonce: [u8; 24] = [0; 24];
+    nonce[0..4].copy_from_slice(suffix);
+    let size = decrypt_into(cipher, &nonce, header, data, buffer)?;
+
+    let extension_len = if packet[0] & 0x10 != 0 {
+        4 * u16::from_be_bytes([header[header_len - 2], header[header_len - 1]]) as usize
+    } else {
+        0
+    };
+    let extension_len = extension_len.min(size);
+    buffer.copy_within(extension_len..size, 0);
+    Ok(size - extension_len)
+}
+
+/// Keeps track of the decoder for a single SSRC along with
+/// the last sequence and timestamp received from it.
+struct DecoderState {
+    decoder: audiopus::coder::Decoder,
+    sequence: u16,
+    timestamp: u32,
+}
+
+impl DecoderState {
+    fn new(sequence: u16, timestamp: u32) -> Result<Self, ProtocolError> {
+        let decoder = audiopus::coder::Decoder::new(
+            audiopus::SampleRate::Hz48000,
+            audiopus::Channels::Stereo,
+        )?;
+        Ok(Self {
+            decoder,
+            // Start one behind so the first packet is considered new
+            sequence: sequence.wrapping_sub(1),
+            timestamp,
+        })
+    }
+
+    /// Whether the packet with the given sequence should be decoded.
+    /// Duplicated and late (out of order) packets are discarded.
+    fn is_newer(&self, sequence: u16) -> bool {
+        (sequence.wrapping_sub(self.sequence) as i16) > 0
+    }
+}
+
+/// Returns the offset of the Opus data in a decrypted payload.
+/// If the RTP header has the extension bit set then the decrypted
+/// data starts with the header extension which has to be skipped.
+/// The rtpsize decrypter already does this itself.
+fn payload_offset(header: &[u8], decrypted: &[u8]) -> usize {
+    let has_extension = header[0] & 0x10 != 0;
+    if has_extension && decrypted.len() >= 4 {
+        let length = u16::from_be_bytes([decrypted[2], decrypted[3]]) as usize;
+        (4 + 4 * length).min(decrypted.len())
+    } else {
+        0
+    }
+}
+
+/// RTCP packets share the socket with RTP packets and have a
+/// packet type within 200..=204 in the second byte.
+fn is_rtcp(packet: &[u8]) -> bool {
+    (200..=204).contains(&packet[1])
+}
+
+/// Returns the function that reverses the packet framing of the given mode
+pub(crate) fn decrypter_for(mode: EncryptionMode) -> Decrypter {
+    match mode {
+        EncryptionMode::XSalsa20Poly1305 => decrypt_xsalsa20_poly1305,
+        EncryptionMode::XSalsa20Poly1305Suffix => decrypt_xsalsa20_poly1305_suffix,
+        EncryptionMode::XSalsa20Poly1305Lite => decrypt_xsalsa20_poly1305_lite,
+        EncryptionMode::AeadXChaCha20Poly1305RtpSize => decrypt_aead_rtpsize,
+    }
+}
+
+/// How often the receive loop checks whether the connection is back up
+const RECONNECT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The parts of the protocol's current session that the receive loop uses. These
+/// are picked up again after a reconnect, which can replace the socket, and after
+/// the secret key is rotated.
+struct ReceiveSession {
+    cipher: VoiceCipher,
+    decrypter: Decrypter,
+    rtpsize: bool,
+    socket: UdpSocket,
+    key_generation: u64,
+}
+
+impl ReceiveSession {
+    fn new(protocol: &DiscordVoiceProtocol) -> Result<Self, ProtocolError> {
+        let socket = protocol.clone_socket()?;
+        // Periodically wake up so we can check whether we've been stopped
+        socket.set_read_timeout(Some(Duration::from_millis(500)))?;
+        Ok(Self {
+            cipher: VoiceCipher::new(protocol.encryption, &protocol.secret_key),
+            decrypter: decrypter_for(protocol.encryption),
+            rtpsize: protocol.encryption.is_rtpsize(),
+            socket,
+            key_generation: protocol.clone_state().key_generation(),
+        })
+    }
+}
+
+fn audio_receive_loop<Callback>(
+    protocol: &Protocol,
+    running: &AtomicBool,
+    callback: &mut Callback,
+) -> Result<(), ProtocolError>
+where
+    Callback: FnMut(u32, Option<u64>, &[i16]),
+{
+    let (mut session, connection, ssrc_map, departed) = {
+        let proto = protocol.lock();
+        (
+            ReceiveSession::new(&proto)?,
+            proto.clone_state(),
+            proto.clone_ssrc_map(),
+            proto.clone_departed_ssrcs(),
+        )
+    };
+    // Users that left before we started listening don't have decoders
+    departed.lock().clear();
+
+    let mut reconnecting = false;
+    let mut packet = [0u8; MAX_BUFFER_SIZE];
+    let mut buffer = [0u8; MAX_BUFFER_SIZE];
+    let mut pcm = [0i16; MAX_DECODED_SAMPLES];
+    let mut decoders: HashMap<u32, DecoderState> = HashMap::new();
+
+    while running.load(Ordering::Relaxed) {
+        if connection.is_disconnected() {
+            reconnecting = true;
+            thread::sleep(RECONNECT_POLL_INTERVAL);
+            continue;
+        }
+        if reconnecting || connection.key_generation() != session.key_generation {
+            session = ReceiveSession::new(&protocol.lock())?;
+            // A new session starts the other users' streams over
+            if reconnecting {
+                decoders.clear();
+                reconnecting = false;
+            }
+        }
+
+        for ssrc in departed.lock().drain(..) {
+            decoders.remove(&ssrc);
+        }
+
+        let size = match session.socket.recv(&mut packet) {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                continue;
+            }
+            Err(e) => return Err(ProtocolError::from(e)),
+            Ok(size) => size,
+        };
+
+        let packet = &packet[..size];
+        if size <= RTP_HEADER_SIZE || is_rtcp(packet) {
+            continue;
+        }
+
+        let sequence = u16::from_be_bytes([packet[2], packet[3]]);
+        let timestamp = u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]);
+        let ssrc = u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]);
+
+        // Packets that don't decrypt or decode are dropped like lost ones
+        let decrypted = match (session.decrypter)(&session.cipher, packet, &mut buffer) {
+            Ok(size) => &buffer[..size],
+            Err(_) => continue,
+        };
+
+        let state = match decoders.entry(ssrc) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(DecoderState::new(sequence, timestamp)?),
+        };
+        if !state.is_newer(sequence) {
+            continue;
+        }
+
+        state.sequence = sequence;
+        state.timestamp = timestamp;
+
+        let offset = if session.rtpsize {
+            0
+        } else {
+            payload_offset(packet, decrypted)
+        };
+        let opus = &decrypted[offset..];
+        let samples = match state.decoder.decode(Some(opus), &mut pcm[..], false) {
+            Ok(samples) => samples,
+            Err(_) => continue,
+        };
+
+        let user_id = ssrc_map.lock().get(&ssrc).copied();
+        callback(ssrc, user_id, &pcm[..samples * CHANNELS as usize]);
+    }
+
+    Ok(())
+}
+
+/// Receives voice from the other users in the channel.
+/// The packets are decoded on a background thread and each decoded
+/// PCM frame (16-bit stereo 48000Hz) is passed to the callback.
+/// If receiving fails the error is kept until it's taken with take_error.
+#[allow(dead_code)]
+pub struct AudioReceiver {
+    thread: thread::JoinHandle<()>,
+    running: Arc<AtomicBool>,
+    error: Arc<Mutex<Option<ProtocolError>>>,
+}
+
+impl AudioReceiver {
+    pub fn new<Callback>(mut callback: Callback, protocol: Protocol) -> Self
+    where
+        Callback: FnMut(u32, Option<u64>, &[i16]),
+        Callback: Send + 'static,
+    {
+        let running = Arc::new(AtomicBool::new(true));
+        let error = Arc::new(Mutex::new(None));
+        let flag = Arc::clone(&running);
+        let failed = Arc::clone(&error);
+        Self {
+            running,
+            error,
+            thread: thread::spawn(move || {
+                if let Err(e) = audio_receive_loop(&protocol, &flag, &mut callback) {
+                    *failed.lock() = Some(e);
+                }
+                flag.store(false, Ordering::Relaxed);
+            }),
+        }
+    }
+
+    pub fn stop(&self) {
+        self.running.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_listening(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    /// The error that stopped the receiver, if it stopped because of one
+    pub fn take_error(&self) -> Option<ProtocolError> {
+        self.error.lock().take()
+    }
+}
@@ -1,5 +1,6 @@
 #![allow(dead_code)]
 use parking_lot::{Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
 // use crossbeam_channel::{bounded, Sender, Receiver};
 
 const DISCONNECTED: u8 = 0;
@@ -11,6 +12,14 @@ const FINISHED: u8 = 4;
 pub struct PlayingState {
     state: Mutex<u8>,
     cond: Condvar,
+    // The paused or finished state to go back to once reconnected, only changed with
+    // the state locked. DISCONNECTED stands for none since it's never restored.
+    restore: AtomicU8,
+    // Set for good once the connection is shut down rather than waiting to reconnect,
+    // only changed with the state locked
+    terminated: AtomicBool,
+    // Bumped every time a session description with a new key is received
+    key_generation: AtomicU64,
 }
 
 impl Default for PlayingState {
@@ -18,6 +27,9 @@ impl Default for PlayingState {
         Self {
             state: Mutex::new(DISCONNECTED),
             cond: Condvar::new(),
+            restore: AtomicU8::new(DISCONNECTED),
+            terminated: AtomicBool::new(false),
+            key_generation: AtomicU64::new(0),
         }
     }
 }
@@ -43,37 +55,91 @@ impl PlayingState {
         *value == PAUSED
     }
 
+    /// Whether the player has been stopped or the connection has been shut down
     pub fn is_finished(&self) -> bool {
         let value = self.state.lock();
-        *value == FINISHED
+        *value == FINISHED || self.terminated.load(Ordering::Relaxed)
     }
 
+    /// Marks the connection as shut down for good, which finishes the player
+    /// even if the state is changed to DISCONNECTED afterwards by closing the websocket.
+    pub fn terminate(&self) {
+        let _guard = self.state.lock();
+        self.terminated.store(true, Ordering::Relaxed);
+        self.cond.notify_all();
+    }
+
+    /// Marks the connection as down. A paused or finished player is remembered
+    /// so that it's still paused or finished once the connection is back up.
     pub fn disconnected(&self) {
         let mut guard = self.state.lock();
+        if *guard == PAUSED || *guard == FINISHED {
+            self.restore.store(*guard, Ordering::Relaxed);
+        }
         *guard = DISCONNECTED;
         self.cond.notify_all();
     }
 
+    /// Marks the connection as up, going back to the paused or finished
+    /// state the player was in before it was disconnected, if any.
     pub fn connected(&self) {
         let mut guard = self.state.lock();
-        *guard = CONNECTED;
+        *guard = match self.restore.swap(DISCONNECTED, Ordering::Relaxed) {
+            DISCONNECTED => CONNECTED,
+            restore => restore,
+        };
         self.cond.notify_all();
     }
 
+    /// Resumes playing. While disconnected this only forgets an earlier pause,
+    /// the player starts playing again by itself once reconnected.
     pub fn playing(&self) {
         let mut guard = self.state.lock();
+        if *guard == DISCONNECTED {
+            self.restore.store(DISCONNECTED, Ordering::Relaxed);
+            return;
+        }
         *guard = PLAYING;
         self.cond.notify_all();
     }
 
+    /// Pauses the player. While disconnected the pause is remembered
+    /// for when the connection is back up instead, so it isn't mistaken
+    /// for the connection having come back.
     pub fn paused(&self) {
         let mut guard = self.state.lock();
+        if *guard == DISCONNECTED {
+            self.restore.store(PAUSED, Ordering::Relaxed);
+            return;
+        }
         *guard = PAUSED;
         self.cond.notify_all();
     }
 
+    /// Marks that audio is being sent, unless the state was changed
+    /// (e.g. to FINISHED by a stop) since the player was created.
+    pub fn start_playing(&self) {
+        let mut guard = self.state.lock();
+        if *guard == CONNECTED {
+            *guard = PLAYING;
+            self.cond.notify_all();
+        }
+    }
+
+    pub fn key_generation(&self) -> u64 {
+        self.key_generation.load(Ordering::Acquire)
+    }
+
+    /// Signals anything encrypting with the old secret key that it has to be rebuilt
+    pub fn key_rotated(&self) {
+        self.key_generation.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Stops the player, which is always done straight away, even while
+    /// disconnected, so a paused or disconnected player thread exits promptly.
     pub fn finished(&self) {
         let mut guard = self.state.lock();
+        self.restore.store(DISCONNECTED, Ordering::Relaxed);
         *guard = FINISHED;
         self.cond.notify_all();
     }
@@ -87,7 +153,16 @@ impl PlayingState {
 
     pub fn wait_until_not_paused(&self) {
         let mut guard = self.state.lock();
-        while *guard == PAUSED {
+        while *guard == PAUSED && !self.terminated.load(Ordering::Relaxed) {
+            self.cond.wait(&mut guard);
+        }
+    }
+
+    /// Waits until the connection is back up or the player has been finished,
+    /// so a shutdown doesn't leave anything waiting for a reconnect that won't come.
+    pub fn wait_while_disconnected(&self) {
+        let mut guard = self.state.lock();
+        while *guard == DISCONNECTED && !self.terminated.load(Ordering::Relaxed) {
             self.cond.wait(&mut guard);
         }
     }
@@ -112,3 +187,53 @@ impl PlayingState {
         self.wait_until_state(FINISHED);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn finished_wakes_a_paused_player() {
+        let state = Arc::new(PlayingState::default());
+        state.connected();
+        state.paused();
+
+        let (done, woken) = mpsc::channel();
+        let waiter = Arc::clone(&state);
+        thread::spawn(move || {
+            waiter.wait_until_not_paused();
+            done.send(()).unwrap();
+        });
+
+        assert!(woken.recv_timeout(Duration::from_millis(50)).is_err());
+        state.finished();
+        assert!(woken.recv_timeout(Duration::from_secs(1)).is_ok());
+        assert!(state.is_finished());
+    }
+
+    #[test]
+    fn pause_survives_reconnecting() {
+        let state = PlayingState::default();
+        state.connected();
+        state.paused();
+        state.disconnected();
+        assert!(state.is_disconnected());
+        state.connected();
+        assert!(state.is_paused());
+    }
+
+    #[test]
+    fn pause_while_disconnected_applies_once_connected() {
+        let state = PlayingState::default();
+        state.connected();
+        state.start_playing();
+        state.disconnected();
+        state.paused();
+        assert!(state.is_disconnected());
+        state.connected();
+        assert!(state.is_paused());
+    }
+}
@@ -0,0 +1,142 @@
+//! The websocket the voice protocol talks over.
+//!
+//! `DiscordVoiceProtocol` only needs to read, write and close messages, so the websocket
+//! is kept behind the `Transport` trait. In production this is a TLS websocket to the
+//! voice server, while in tests `MockTransport` feeds the protocol queued payloads in
+//! memory so `poll` can be driven without a server.
+
+use tungstenite::error::Error as TungError;
+use tungstenite::protocol::{frame::CloseFrame, WebSocket};
+use tungstenite::Message;
+
+use std::net::TcpStream;
+use std::time::Duration;
+
+use native_tls::TlsStream;
+
+#[cfg(test)]
+pub use self::mock::MockTransport;
+
+pub trait Transport: Send {
+    /// Reads the next message. Fails with an Io error of kind WouldBlock or TimedOut
+    /// if there is none within the read timeout, or straight away if non-blocking.
+    fn read_message(&mut self) -> Result<Message, TungError>;
+
+    fn write_message(&mut self, message: Message) -> Result<(), TungError>;
+
+    fn close(&mut self, frame: Option<CloseFrame<'static>>) -> Result<(), TungError>;
+
+    /// Sets how long read_message waits for a message, None waiting forever
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> std::io::Result<()>;
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> std::io::Result<()>;
+}
+
+impl Transport for WebSocket<TlsStream<TcpStream>> {
+    fn read_message(&mut self) -> Result<Message, TungError> {
+        WebSocket::read_message(self)
+    }
+
+    fn write_message(&mut self, message: Message) -> Result<(), TungError> {
+        WebSocket::write_message(self, message)
+    }
+
+    fn close(&mut self, frame: Option<CloseFrame<'static>>) -> Result<(), TungError> {
+        WebSocket::close(self, frame)
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.get_ref().get_ref().set_read_timeout(timeout)
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> std::io::Result<()> {
+        self.get_ref().get_ref().set_nonblocking(nonblocking)
+    }
+}
+
+#[cfg(test)]
+mod mock {
+    use super::Transport;
+
+    use tungstenite::error::Error as TungError;
+    use tungstenite::protocol::frame::CloseFrame;
+    use tungstenite::Message;
+
+    use std::collections::VecDeque;
+    use std::io::{Error, ErrorKind};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use parking_lot::Mutex;
+
+    /// A transport that never touches the network. Messages queued with `push` are
+    /// read in order, and once there are none left reads fail with WouldBlock, the same
+    /// as a real websocket whose read timed out. Everything the protocol writes is kept.
+    /// Clones share their queues so a clone can be kept to feed and inspect the
+    /// transport after the original is handed to the protocol.
+    #[derive(Clone, Default)]
+    pub struct MockTransport {
+        incoming: Arc<Mutex<VecDeque<Message>>>,
+        sent: Arc<Mutex<Vec<Message>>>,
+        close_frame: Arc<Mutex<Option<Option<CloseFrame<'static>>>>>,
+    }
+
+    impl MockTransport {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Queues a message to be read by the protocol
+        pub fn push(&self, message: Message) {
+            self.incoming.lock().push_back(message);
+        }
+
+        /// Queues a text payload, e.g. `{"op": 8, "d": {"heartbeat_interval": 41250}}`
+        pub fn push_text(&self, text: &str) {
+            self.push(Message::text(text));
+        }
+
+        /// Takes the messages the protocol has written so far
+        pub fn take_sent(&self) -> Vec<Message> {
+            std::mem::take(&mut *self.sent.lock())
+        }
+
+        /// The frame the protocol closed the transport with, if it has closed it
+        pub fn close_frame(&self) -> Option<Option<CloseFrame<'static>>> {
+            self.close_frame.lock().clone()
+        }
+    }
+
+    impl Transport for MockTransport {
+        fn read_message(&mut self) -> Result<Message, TungError> {
+            match self.incoming.lock().pop_front() {
+                Some(message) => Ok(message),
+                None => Err(TungError::Io(Error::new(
+                    ErrorKind::WouldBlock,
+                    "no queued messages",
+                ))),
+            }
+        }
+
+        fn write_message(&mut self, message: Message) -> Result<(), TungError> {
+            if self.close_frame.lock().is_some() {
+                return Err(TungError::AlreadyClosed);
+            }
+            self.sent.lock().push(message);
+            Ok(())
+        }
+
+        fn close(&mut self, frame: Option<CloseFrame<'static>>) -> Result<(), TungError> {
+            *self.close_frame.lock() = Some(frame);
+            Ok(())
+        }
+
+        fn set_read_timeout(&mut self, _timeout: Option<Duration>) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn set_nonblocking(&mut self, _nonblocking: bool) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+}